@@ -0,0 +1,521 @@
+mod dmc;
+mod envelope;
+mod filter;
+mod length_counter;
+mod mixer;
+mod noise;
+mod output;
+mod pulse;
+mod resampler;
+mod triangle;
+
+use dmc::Dmc;
+use mixer::Mixer;
+use noise::Noise;
+use output::AudioOutput;
+use pulse::Pulse;
+use triangle::Triangle;
+
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+/// Host sample rate of the audio output unless set otherwise
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+/// One of the five APU channels
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Channel {
+    Pulse1 = 0,
+    Pulse2 = 1,
+    Triangle = 2,
+    Noise = 3,
+    Dmc = 4,
+}
+
+impl Channel {
+    /// All the channels, in register order
+    pub const ALL: [Channel; 5] = [
+        Channel::Pulse1,
+        Channel::Pulse2,
+        Channel::Triangle,
+        Channel::Noise,
+        Channel::Dmc,
+    ];
+
+    /// Lower case name, e.g. "pulse1"
+    pub fn name(self) -> &'static str {
+        match self {
+            Channel::Pulse1 => "pulse1",
+            Channel::Pulse2 => "pulse2",
+            Channel::Triangle => "triangle",
+            Channel::Noise => "noise",
+            Channel::Dmc => "dmc",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum SequencerMode {
+    FourStep,
+    FiveStep,
+}
+
+/*
+ * 2A03 APU ($4000-$4013, $4015, $4017)
+ *
+ * Clocked once per CPU cycle. The frame counter divides the CPU clock
+ * into quarter and half frames that drive the envelopes, the linear
+ * counter, the length counters and the sweep units.
+ *
+ * The mixed output is resampled to the host sample rate and buffered
+ * until the frontend reads it.
+ */
+pub struct Apu {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    mixer: Mixer,
+    output: AudioOutput,
+    /* each channel on its own (empty unless enabled) */
+    channel_outputs: Vec<AudioOutput>,
+    cycles: u64, // CPU cycles since power on
+    mode: SequencerMode,
+    irq_inhibit: bool,
+    frame_irq: bool,
+    frame_cycle: u32,
+    /* a $4017 write resets the sequencer 3 or 4 CPU cycles later */
+    frame_reset_delay: Option<u8>,
+}
+
+impl Apu {
+    /* CPU cycles of the frame counter steps */
+    const STEP1: u32 = 7457;
+    const STEP2: u32 = 14913;
+    const STEP3: u32 = 22371;
+    const STEP4: u32 = 29829;
+    const STEP5: u32 = 37281;
+
+    pub fn new() -> Apu {
+        Apu {
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::new(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            mixer: Mixer::new(),
+            output: AudioOutput::new(DEFAULT_SAMPLE_RATE),
+            channel_outputs: Vec::new(),
+            cycles: 0,
+            mode: SequencerMode::FourStep,
+            irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+            frame_reset_delay: None,
+        }
+    }
+
+    /*
+     * Level of the APU's IRQ output (true: asserted)
+     */
+    pub fn irq(&self) -> bool {
+        self.frame_irq || self.dmc.irq()
+    }
+
+    /*
+     * Address of the sample byte the DMC is waiting for, if any.
+     * The CPU bus fetches it and hands it over with dmc_fill().
+     */
+    pub fn dmc_fetch_addr(&self) -> Option<u16> {
+        self.dmc.fetch_addr()
+    }
+
+    pub fn dmc_fill(&mut self, data: u8) {
+        self.dmc.fill(data);
+    }
+
+    /*
+     * Advance the APU by one CPU cycle
+     */
+    pub fn step(&mut self) {
+        self.cycles += 1;
+
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if self.cycles & 1 == 0 {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+
+        self.step_frame_counter();
+
+        let (p1, p2, t, n, d) = (
+            self.pulse1.output(),
+            self.pulse2.output(),
+            self.triangle.output(),
+            self.noise.output(),
+            self.dmc.output(),
+        );
+        self.output.push(self.mixer.mix(p1, p2, t, n, d));
+
+        if !self.channel_outputs.is_empty() {
+            /* mixed alone, so each file has the level it has in the mix */
+            let levels = [
+                self.mixer.mix(p1, 0, 0, 0, 0),
+                self.mixer.mix(0, p2, 0, 0, 0),
+                self.mixer.mix(0, 0, t, 0, 0),
+                self.mixer.mix(0, 0, 0, n, 0),
+                self.mixer.mix(0, 0, 0, 0, d),
+            ];
+            for (output, level) in self.channel_outputs.iter_mut().zip(levels.iter()) {
+                output.push(*level);
+            }
+        }
+    }
+
+    /*
+     * Change the host sample rate (discards the buffered samples)
+     */
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.output = AudioOutput::new(sample_rate);
+        if !self.channel_outputs.is_empty() {
+            self.set_channel_outputs(true);
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.output.sample_rate()
+    }
+
+    /*
+     * Number of samples ready to be read
+     */
+    pub fn samples_available(&self) -> usize {
+        self.output.available()
+    }
+
+    /*
+     * Move up to out.len() samples (-1.0 to 1.0) into out and return
+     * how many were written
+     */
+    pub fn read_samples(&mut self, out: &mut [f32]) -> usize {
+        self.output.read(out)
+    }
+
+    /*
+     * Also produce a separate stream for each channel (for debugging)
+     */
+    pub fn set_channel_outputs(&mut self, enabled: bool) {
+        let sample_rate = self.sample_rate();
+        self.channel_outputs = if enabled {
+            Channel::ALL
+                .iter()
+                .map(|_| AudioOutput::new(sample_rate))
+                .collect()
+        } else {
+            Vec::new()
+        };
+    }
+
+    /*
+     * Like read_samples(), for the stream of a single channel.
+     * Returns 0 if the channel outputs are not enabled.
+     */
+    pub fn read_channel_samples(&mut self, channel: Channel, out: &mut [f32]) -> usize {
+        match self.channel_outputs.get_mut(channel as usize) {
+            Some(output) => output.read(out),
+            None => 0,
+        }
+    }
+
+    fn step_frame_counter(&mut self) {
+        if let Some(delay) = self.frame_reset_delay {
+            if delay > 1 {
+                self.frame_reset_delay = Some(delay - 1);
+            } else {
+                self.frame_reset_delay = None;
+                self.frame_cycle = 0;
+                /* entering the 5-step mode clocks everything immediately */
+                if self.mode == SequencerMode::FiveStep {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+                return;
+            }
+        }
+
+        self.frame_cycle += 1;
+        match (self.mode, self.frame_cycle) {
+            (_, Self::STEP1) | (_, Self::STEP3) => {
+                self.clock_quarter_frame();
+            }
+            (_, Self::STEP2) => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            (SequencerMode::FourStep, c) if c == Self::STEP4 - 1 => {
+                self.set_frame_irq();
+            }
+            (SequencerMode::FourStep, Self::STEP4) => {
+                self.set_frame_irq();
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            (SequencerMode::FourStep, c) if c == Self::STEP4 + 1 => {
+                self.set_frame_irq();
+                self.frame_cycle = 0;
+            }
+            (SequencerMode::FiveStep, Self::STEP5) => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            (SequencerMode::FiveStep, c) if c == Self::STEP5 + 1 => {
+                self.frame_cycle = 0;
+            }
+            _ => {}
+        }
+    }
+
+    fn set_frame_irq(&mut self) {
+        if !self.irq_inhibit {
+            self.frame_irq = true;
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulse1.clock_quarter_frame();
+        self.pulse2.clock_quarter_frame();
+        self.triangle.clock_quarter_frame();
+        self.noise.clock_quarter_frame();
+    }
+
+    fn clock_half_frame(&mut self) {
+        self.pulse1.clock_half_frame();
+        self.pulse2.clock_half_frame();
+        self.triangle.clock_half_frame();
+        self.noise.clock_half_frame();
+    }
+
+    /*
+     * $4015 read: length counter status and interrupt flags.
+     * Reading acknowledges the frame interrupt.
+     */
+    pub fn read_status(&mut self) -> u8 {
+        let mut data = 0;
+        if self.pulse1.length_active() {
+            data |= 0b0000_0001;
+        }
+        if self.pulse2.length_active() {
+            data |= 0b0000_0010;
+        }
+        if self.triangle.length_active() {
+            data |= 0b0000_0100;
+        }
+        if self.noise.length_active() {
+            data |= 0b0000_1000;
+        }
+        if self.dmc.active() {
+            data |= 0b0001_0000;
+        }
+        if self.frame_irq {
+            data |= 0b0100_0000;
+        }
+        if self.dmc.irq() {
+            data |= 0b1000_0000;
+        }
+
+        self.frame_irq = false;
+        data
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4000..=0x4003 => self.pulse1.write(addr - 0x4000, data),
+            0x4004..=0x4007 => self.pulse2.write(addr - 0x4004, data),
+            0x4008..=0x400B => self.triangle.write(addr - 0x4008, data),
+            0x400C..=0x400F => self.noise.write(addr - 0x400C, data),
+            0x4010..=0x4013 => self.dmc.write(addr - 0x4010, data),
+            0x4015 => {
+                // ---D NT21
+                self.pulse1.set_enabled((data & 0b0000_0001) != 0);
+                self.pulse2.set_enabled((data & 0b0000_0010) != 0);
+                self.triangle.set_enabled((data & 0b0000_0100) != 0);
+                self.noise.set_enabled((data & 0b0000_1000) != 0);
+                self.dmc.set_enabled((data & 0b0001_0000) != 0);
+            }
+            0x4017 => {
+                // MI-- ----
+                self.mode = if (data & 0b1000_0000) != 0 {
+                    SequencerMode::FiveStep
+                } else {
+                    SequencerMode::FourStep
+                };
+                self.irq_inhibit = (data & 0b0100_0000) != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+                /* the write takes effect on the next even (APU) cycle */
+                self.frame_reset_delay = Some(if self.cycles & 1 == 0 { 3 } else { 4 });
+            }
+            _ => {}
+        }
+    }
+
+    /*
+     * The audio output buffers are not part of the state
+     */
+    pub fn save_state(&self, w: &mut StateWriter) {
+        self.pulse1.save_state(w);
+        self.pulse2.save_state(w);
+        self.triangle.save_state(w);
+        self.noise.save_state(w);
+        self.dmc.save_state(w);
+        w.write_u64(self.cycles);
+        w.write_bool(self.mode == SequencerMode::FiveStep);
+        w.write_bool(self.irq_inhibit);
+        w.write_bool(self.frame_irq);
+        w.write_u32(self.frame_cycle);
+        w.write_option_u8(self.frame_reset_delay);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.pulse1.load_state(r)?;
+        self.pulse2.load_state(r)?;
+        self.triangle.load_state(r)?;
+        self.noise.load_state(r)?;
+        self.dmc.load_state(r)?;
+        self.cycles = r.read_u64()?;
+        self.mode = if r.read_bool()? {
+            SequencerMode::FiveStep
+        } else {
+            SequencerMode::FourStep
+        };
+        self.irq_inhibit = r.read_bool()?;
+        self.frame_irq = r.read_bool()?;
+        self.frame_cycle = r.read_u32()?;
+        self.frame_reset_delay = r.read_option_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(apu: &mut Apu, cycles: u32) {
+        for _ in 0..cycles {
+            apu.step();
+        }
+    }
+
+    #[test]
+    fn test_frame_irq() {
+        let mut apu = Apu::new();
+
+        run(&mut apu, Apu::STEP4 - 2);
+        assert!(!apu.irq());
+        run(&mut apu, 1);
+        assert!(apu.irq());
+
+        // reading $4015 acknowledges it
+        assert_eq!(apu.read_status() & 0b0100_0000, 0b0100_0000);
+        assert!(!apu.irq());
+
+        // ... but it is set again on the following two cycles
+        run(&mut apu, 2);
+        assert!(apu.irq());
+
+        // the 5-step mode never raises it
+        let mut apu = Apu::new();
+        apu.write(0x4017, 0b1000_0000);
+        run(&mut apu, Apu::STEP5 * 2);
+        assert!(!apu.irq());
+
+        // the inhibit flag clears it
+        let mut apu = Apu::new();
+        run(&mut apu, Apu::STEP4);
+        assert!(apu.irq());
+        apu.write(0x4017, 0b0100_0000);
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn test_length_counter() {
+        let mut apu = Apu::new();
+
+        // disabled channels ignore the length load
+        apu.write(0x4003, 0b0000_1000);
+        assert_eq!(apu.read_status(), 0);
+
+        apu.write(0x4015, 0b0000_1111);
+        apu.write(0x4003, 0b0000_1000); // 254
+        apu.write(0x4007, 0b0001_1000); // 2
+        apu.write(0x400B, 0b0000_1000);
+        apu.write(0x400F, 0b0000_1000);
+        assert_eq!(apu.read_status(), 0b0000_1111);
+
+        // two half frames (5-step mode: one immediately, one at step 2)
+        apu.write(0x4017, 0b1100_0000);
+        run(&mut apu, 4 + Apu::STEP2);
+        assert_eq!(apu.read_status(), 0b0000_1101);
+
+        // disabling clears the counter
+        apu.write(0x4015, 0b0000_0001);
+        assert_eq!(apu.read_status(), 0b0000_0001);
+    }
+
+    #[test]
+    fn test_channel_outputs() {
+        let mut apu = Apu::new();
+        apu.set_channel_outputs(true);
+        apu.write(0x4015, 0b0000_0001);
+        apu.write(0x4000, 0b1011_1111); // 50% duty, constant volume 15
+        apu.write(0x4002, 0xFD); // ~440Hz
+        apu.write(0x4003, 0x00);
+        run(&mut apu, 29830);
+
+        let mut mixed = [0.0; 1024];
+        let mut pulse1 = [0.0; 1024];
+        let mut pulse2 = [0.0; 1024];
+        let mut triangle = [0.0; 1024];
+        let n = apu.read_samples(&mut mixed);
+        assert_eq!(apu.read_channel_samples(Channel::Pulse1, &mut pulse1), n);
+        assert_eq!(apu.read_channel_samples(Channel::Pulse2, &mut pulse2), n);
+        assert_eq!(
+            apu.read_channel_samples(Channel::Triangle, &mut triangle),
+            n
+        );
+
+        assert!(pulse1[..n].iter().any(|&s| s.abs() > 0.01));
+        assert!(pulse2[..n].iter().all(|&s| s == 0.0));
+
+        // the pulse and TND groups add up (the triangle holds level 15 at power on)
+        for i in 0..n {
+            assert!((mixed[i] - (pulse1[i] + triangle[i])).abs() < 0.0001);
+        }
+    }
+
+    #[test]
+    fn test_triangle_linear_counter() {
+        let mut apu = Apu::new();
+        apu.write(0x4015, 0b0000_0100);
+        apu.write(0x4008, 0x01); // linear counter 1
+        apu.write(0x400A, 0x00);
+        apu.write(0x400B, 0x08);
+
+        // not running until the first quarter frame loads the linear counter
+        run(&mut apu, 100);
+        assert_eq!(apu.triangle.output(), 15);
+
+        run(&mut apu, Apu::STEP1 - 100);
+        run(&mut apu, 10);
+        assert_ne!(apu.triangle.output(), 15);
+
+        // the next quarter frame stops it
+        run(&mut apu, Apu::STEP2 - Apu::STEP1);
+        let level = apu.triangle.output();
+        run(&mut apu, 10);
+        assert_eq!(apu.triangle.output(), level);
+    }
+}
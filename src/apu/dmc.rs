@@ -0,0 +1,290 @@
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+/* timer periods in CPU cycles (NTSC) */
+const RATE_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+/*
+ * Delta modulation channel ($4010-$4013)
+ *
+ * Plays 1-bit delta encoded samples read from $C000-$FFFF. The memory
+ * reader does not access the bus by itself: the CPU bus polls
+ * fetch_addr() and hands the byte back with fill(), halting the CPU
+ * while it does so.
+ */
+pub struct Dmc {
+    irq_enabled: bool,
+    looping: bool,
+    timer_period: u16,
+    timer: u16,
+    level: u8, // 7-bit output level
+    sample_addr: u16,
+    sample_length: u16,
+    current_addr: u16,
+    bytes_remaining: u16,
+    buffer: Option<u8>,
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+    irq: bool,
+}
+
+impl Dmc {
+    pub fn new() -> Dmc {
+        Dmc {
+            irq_enabled: false,
+            looping: false,
+            timer_period: RATE_TABLE[0],
+            timer: 0,
+            level: 0,
+            sample_addr: 0xC000,
+            sample_length: 1,
+            current_addr: 0xC000,
+            bytes_remaining: 0,
+            buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+            irq: false,
+        }
+    }
+
+    pub fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                // IL-- RRRR
+                self.irq_enabled = (data & 0b1000_0000) != 0;
+                self.looping = (data & 0b0100_0000) != 0;
+                self.timer_period = RATE_TABLE[(data & 0b1111) as usize];
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+            }
+            1 => {
+                // -DDD DDDD: direct load
+                self.level = data & 0b0111_1111;
+            }
+            2 => {
+                // %11AAAAAA.AA000000
+                self.sample_addr = 0xC000 | ((data as u16) << 6);
+            }
+            3 => {
+                // %LLLL.LLLL0001
+                self.sample_length = ((data as u16) << 4) | 1;
+            }
+            _ => {}
+        }
+    }
+
+    /*
+     * $4015 bit 4: stop, or (re)start the sample if it has finished.
+     * Any $4015 write acknowledges the DMC interrupt.
+     */
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_addr = self.sample_addr;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    pub fn active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    pub fn irq(&self) -> bool {
+        self.irq
+    }
+
+    /*
+     * Address the memory reader wants to fetch, if the sample buffer
+     * is empty and there are bytes left to play
+     */
+    pub fn fetch_addr(&self) -> Option<u16> {
+        if self.buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_addr)
+        } else {
+            None
+        }
+    }
+
+    /*
+     * Complete the fetch requested by fetch_addr()
+     */
+    pub fn fill(&mut self, data: u8) {
+        self.buffer = Some(data);
+        /* the address wraps around to $8000, not $C000 */
+        self.current_addr = if self.current_addr == 0xFFFF {
+            0x8000
+        } else {
+            self.current_addr + 1
+        };
+
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    /* clocked every CPU cycle */
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            self.clock_output();
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_output(&mut self) {
+        if !self.silence {
+            if (self.shift & 1) != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.buffer.take() {
+                Some(data) => {
+                    self.silence = false;
+                    self.shift = data;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.irq_enabled);
+        w.write_bool(self.looping);
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer);
+        w.write_u8(self.level);
+        w.write_u16(self.sample_addr);
+        w.write_u16(self.sample_length);
+        w.write_u16(self.current_addr);
+        w.write_u16(self.bytes_remaining);
+        w.write_option_u8(self.buffer);
+        w.write_u8(self.shift);
+        w.write_u8(self.bits_remaining);
+        w.write_bool(self.silence);
+        w.write_bool(self.irq);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.irq_enabled = r.read_bool()?;
+        self.looping = r.read_bool()?;
+        self.timer_period = r.read_u16()?;
+        self.timer = r.read_u16()?;
+        self.level = r.read_u8()? & 0b0111_1111;
+        self.sample_addr = r.read_u16()?;
+        self.sample_length = r.read_u16()?;
+        self.current_addr = r.read_u16()?;
+        self.bytes_remaining = r.read_u16()?;
+        self.buffer = r.read_option_u8()?;
+        self.shift = r.read_u8()?;
+        self.bits_remaining = r.read_u8()?;
+        self.silence = r.read_bool()?;
+        self.irq = r.read_bool()?;
+        if self.timer_period == 0 || self.bits_remaining == 0 || self.bits_remaining > 8 {
+            return Err(StateError::Corrupted("DMC timer"));
+        }
+        Ok(())
+    }
+
+    /* 0-127 */
+    pub fn output(&self) -> u8 {
+        self.level
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* run the channel, serving its fetches from the given sample */
+    fn play(dmc: &mut Dmc, sample: &[u8], cycles: usize) -> usize {
+        let mut fetches = 0;
+        for _ in 0..cycles {
+            if let Some(addr) = dmc.fetch_addr() {
+                dmc.fill(sample[(addr - 0xC000) as usize % sample.len()]);
+                fetches += 1;
+            }
+            dmc.clock_timer();
+        }
+        fetches
+    }
+
+    #[test]
+    fn test_sample_playback() {
+        let mut dmc = Dmc::new();
+        dmc.write(0, 0b1000_1111); // IRQ, rate 54
+        dmc.write(1, 64);
+        dmc.write(2, 0x00);
+        dmc.write(3, 0x01); // 17 bytes
+        dmc.set_enabled(true);
+        assert!(dmc.active());
+
+        // all 1s: the level rises by 2 per bit as long as it stays below 128
+        let fetches = play(&mut dmc, &[0xFF], 54 * 8 * 4);
+        assert_eq!(fetches, 5);
+        assert!(dmc.output() > 64);
+
+        play(&mut dmc, &[0xFF], 54 * 8 * 20);
+        assert!(!dmc.active());
+        assert!(dmc.irq());
+        assert_eq!(dmc.output(), 126);
+
+        // $4015 write acknowledges
+        dmc.set_enabled(false);
+        assert!(!dmc.irq());
+    }
+
+    #[test]
+    fn test_loop() {
+        let mut dmc = Dmc::new();
+        dmc.write(0, 0b1100_1111); // IRQ, loop, rate 54
+        dmc.write(3, 0x00); // 1 byte
+        dmc.set_enabled(true);
+
+        // all 0s: the level falls to 0 and stays there
+        dmc.write(1, 10);
+        play(&mut dmc, &[0x00], 54 * 8 * 4);
+        assert!(dmc.active());
+        assert!(!dmc.irq());
+        assert_eq!(dmc.output(), 0);
+        assert_eq!(dmc.fetch_addr(), None);
+    }
+
+    #[test]
+    fn test_address_wrap() {
+        let mut dmc = Dmc::new();
+        dmc.write(2, 0xFF); // $FFC0
+        dmc.write(3, 0x04); // 65 bytes
+        dmc.set_enabled(true);
+
+        for _ in 0..64 {
+            dmc.fill(0);
+            dmc.buffer = None;
+        }
+        assert_eq!(dmc.fetch_addr(), Some(0x8000));
+    }
+}
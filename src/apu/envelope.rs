@@ -0,0 +1,87 @@
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+/*
+ * Envelope generator shared by the pulse and noise channels
+ *
+ * Produces either a constant volume or a saw decaying from 15 to 0,
+ * clocked by the quarter frames of the frame counter.
+ */
+pub struct Envelope {
+    start: bool,
+    looping: bool, // doubles as the length counter halt flag
+    constant: bool,
+    period: u8, // also the constant volume
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Envelope {
+        Envelope {
+            start: false,
+            looping: false,
+            constant: false,
+            period: 0,
+            divider: 0,
+            decay: 0,
+        }
+    }
+
+    /*
+     * --LC VVVV of $4000/$4004/$400C
+     */
+    pub fn write_control(&mut self, data: u8) {
+        self.looping = (data & 0b0010_0000) != 0;
+        self.constant = (data & 0b0001_0000) != 0;
+        self.period = data & 0b0000_1111;
+    }
+
+    /* restarted by writes to the length counter load register */
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.period;
+        } else if self.divider == 0 {
+            self.divider = self.period;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.start);
+        w.write_bool(self.looping);
+        w.write_bool(self.constant);
+        w.write_u8(self.period);
+        w.write_u8(self.divider);
+        w.write_u8(self.decay);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.start = r.read_bool()?;
+        self.looping = r.read_bool()?;
+        self.constant = r.read_bool()?;
+        self.period = r.read_u8()?;
+        self.divider = r.read_u8()?;
+        self.decay = r.read_u8()?;
+        Ok(())
+    }
+
+    pub fn volume(&self) -> u8 {
+        if self.constant {
+            self.period
+        } else {
+            self.decay
+        }
+    }
+}
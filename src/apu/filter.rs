@@ -0,0 +1,84 @@
+use std::f32::consts::PI;
+
+/*
+ * First-order RC filter
+ */
+struct Filter {
+    high_pass: bool,
+    alpha: f32,
+    prev_in: f32,
+    prev_out: f32,
+}
+
+impl Filter {
+    fn new(high_pass: bool, cutoff: f32, sample_rate: f32) -> Filter {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate;
+        Filter {
+            high_pass,
+            alpha: if high_pass {
+                rc / (rc + dt)
+            } else {
+                dt / (rc + dt)
+            },
+            prev_in: 0.0,
+            prev_out: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let out = if self.high_pass {
+            self.alpha * (self.prev_out + input - self.prev_in)
+        } else {
+            self.prev_out + self.alpha * (input - self.prev_out)
+        };
+        self.prev_in = input;
+        self.prev_out = out;
+        out
+    }
+}
+
+/*
+ * The filters between the APU and the audio output of the console:
+ * a 90Hz and a 440Hz high-pass followed by a 14kHz low-pass
+ */
+pub struct OutputFilter {
+    filters: [Filter; 3],
+}
+
+impl OutputFilter {
+    pub fn new(sample_rate: u32) -> OutputFilter {
+        let rate = sample_rate as f32;
+        OutputFilter {
+            filters: [
+                Filter::new(true, 90.0, rate),
+                Filter::new(true, 440.0, rate),
+                Filter::new(false, 14000.0, rate),
+            ],
+        }
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        self.filters
+            .iter_mut()
+            .fold(input, |sample, filter| filter.process(sample))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filters() {
+        // a low-pass lets DC through
+        let mut low = Filter::new(false, 14000.0, 44100.0);
+        let out = (0..1000).map(|_| low.process(1.0)).last().unwrap();
+        assert!((out - 1.0).abs() < 0.001);
+
+        // the high-passes remove it
+        let mut output = OutputFilter::new(44100);
+        let out = (0..44100).map(|_| output.process(1.0)).last().unwrap();
+        assert!(out.abs() < 0.001);
+    }
+}
@@ -0,0 +1,69 @@
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+/*
+ * Length counter: silences the channel when it reaches 0.
+ * Clocked by the half frames of the frame counter unless halted.
+ */
+pub struct LengthCounter {
+    enabled: bool, // $4015
+    halt: bool,
+    counter: u8,
+}
+
+impl LengthCounter {
+    pub fn new() -> LengthCounter {
+        LengthCounter {
+            enabled: false,
+            halt: false,
+            counter: 0,
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    pub fn set_halt(&mut self, halt: bool) {
+        self.halt = halt;
+    }
+
+    /*
+     * LLLL L--- of $4003/$4007/$400B/$400F (ignored while disabled)
+     */
+    pub fn load(&mut self, data: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(data >> 3) as usize];
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.counter > 0
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_bool(self.halt);
+        w.write_u8(self.counter);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.read_bool()?;
+        self.halt = r.read_bool()?;
+        self.counter = r.read_u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,60 @@
+/*
+ * Non-linear mixer of the 2A03
+ *
+ * The pulse channels share one DAC and the triangle, noise and DMC
+ * another. Their outputs are approximated with the lookup tables from
+ * the NESdev wiki:
+ *
+ *   pulse_out = 95.52 / (8128 / (pulse1 + pulse2) + 100)
+ *   tnd_out   = 163.67 / (24329 / (3 * triangle + 2 * noise + dmc) + 100)
+ */
+pub struct Mixer {
+    pulse_table: [f32; 31],
+    tnd_table: [f32; 203],
+}
+
+impl Mixer {
+    pub fn new() -> Mixer {
+        let mut pulse_table = [0.0; 31];
+        for (n, out) in pulse_table.iter_mut().enumerate().skip(1) {
+            *out = 95.52 / (8128.0 / n as f32 + 100.0);
+        }
+
+        let mut tnd_table = [0.0; 203];
+        for (n, out) in tnd_table.iter_mut().enumerate().skip(1) {
+            *out = 163.67 / (24329.0 / n as f32 + 100.0);
+        }
+
+        Mixer {
+            pulse_table,
+            tnd_table,
+        }
+    }
+
+    /*
+     * pulse1, pulse2, triangle and noise are 0-15, dmc is 0-127.
+     * The result is in 0.0-1.0.
+     */
+    pub fn mix(&self, pulse1: u8, pulse2: u8, triangle: u8, noise: u8, dmc: u8) -> f32 {
+        let pulse = pulse1 as usize + pulse2 as usize;
+        let tnd = 3 * triangle as usize + 2 * noise as usize + dmc as usize;
+        self.pulse_table[pulse] + self.tnd_table[tnd]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mix() {
+        let mixer = Mixer::new();
+        assert_eq!(mixer.mix(0, 0, 0, 0, 0), 0.0);
+        assert!((mixer.mix(15, 15, 0, 0, 0) - 0.2575).abs() < 0.0001);
+        assert!((mixer.mix(0, 0, 15, 15, 127) - 0.7424).abs() < 0.0001);
+
+        // non-linear: two channels together are quieter than the sum
+        let one = mixer.mix(15, 0, 0, 0, 0);
+        assert!(mixer.mix(15, 15, 0, 0, 0) < 2.0 * one);
+    }
+}
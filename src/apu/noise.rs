@@ -0,0 +1,143 @@
+use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+/* timer periods in CPU cycles (NTSC) */
+const PERIOD_TABLE: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+/*
+ * Noise channel ($400C-$400F)
+ *
+ * A 15-bit linear feedback shift register. Mode 1 takes the feedback
+ * from bit 6 instead of bit 1, which gives a short, metallic sequence.
+ */
+pub struct Noise {
+    envelope: Envelope,
+    length: LengthCounter,
+    mode: bool,
+    shift: u16,
+    timer_period: u16,
+    timer: u16,
+}
+
+impl Noise {
+    pub fn new() -> Noise {
+        Noise {
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+            mode: false,
+            shift: 1,
+            timer_period: PERIOD_TABLE[0],
+            timer: 0,
+        }
+    }
+
+    pub fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                // --LC VVVV
+                self.length.set_halt((data & 0b0010_0000) != 0);
+                self.envelope.write_control(data);
+            }
+            2 => {
+                // M--- PPPP
+                self.mode = (data & 0b1000_0000) != 0;
+                self.timer_period = PERIOD_TABLE[(data & 0b1111) as usize];
+            }
+            3 => {
+                // LLLL L---
+                self.length.load(data);
+                self.envelope.restart();
+            }
+            _ => {}
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.length.set_enabled(enabled);
+    }
+
+    pub fn length_active(&self) -> bool {
+        self.length.active()
+    }
+
+    /* clocked every CPU cycle */
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            self.clock_shift();
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_shift(&mut self) {
+        let tap = if self.mode { 6 } else { 1 };
+        let feedback = (self.shift ^ (self.shift >> tap)) & 1;
+        self.shift = (self.shift >> 1) | (feedback << 14);
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        self.envelope.save_state(w);
+        self.length.save_state(w);
+        w.write_bool(self.mode);
+        w.write_u16(self.shift);
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.envelope.load_state(r)?;
+        self.length.load_state(r)?;
+        self.mode = r.read_bool()?;
+        self.shift = r.read_u16()?;
+        self.timer_period = r.read_u16()?;
+        self.timer = r.read_u16()?;
+        if self.timer_period == 0 {
+            return Err(StateError::Corrupted("noise period"));
+        }
+        Ok(())
+    }
+
+    /* 0-15 */
+    pub fn output(&self) -> u8 {
+        if (self.shift & 1) != 0 || !self.length.active() {
+            0
+        } else {
+            self.envelope.volume()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequence_length(mode: bool) -> usize {
+        let mut noise = Noise::new();
+        noise.mode = mode;
+        let start = noise.shift;
+        (1..=0x8000)
+            .find(|_| {
+                noise.clock_shift();
+                noise.shift == start
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_lfsr_period() {
+        assert_eq!(sequence_length(false), 32767);
+        assert_eq!(sequence_length(true), 93);
+    }
+}
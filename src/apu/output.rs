@@ -0,0 +1,44 @@
+use super::filter::OutputFilter;
+use super::resampler::Resampler;
+
+/*
+ * One audio stream at the host sample rate: the resampler followed by
+ * the output filters of the console
+ */
+pub struct AudioOutput {
+    resampler: Resampler,
+    filter: OutputFilter,
+}
+
+impl AudioOutput {
+    pub fn new(sample_rate: u32) -> AudioOutput {
+        AudioOutput {
+            resampler: Resampler::new(sample_rate),
+            filter: OutputFilter::new(sample_rate),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.resampler.sample_rate()
+    }
+
+    /* the mixer output of one CPU cycle */
+    pub fn push(&mut self, level: f32) {
+        self.resampler.push(level);
+    }
+
+    pub fn available(&self) -> usize {
+        self.resampler.available()
+    }
+
+    pub fn read(&mut self, out: &mut [f32]) -> usize {
+        let len = out.len();
+        let filter = &mut self.filter;
+        let mut samples = out.iter_mut();
+        self.resampler.drain(len, |sample| {
+            if let Some(s) = samples.next() {
+                *s = filter.process(sample);
+            }
+        })
+    }
+}
@@ -0,0 +1,228 @@
+use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0], // 12.5%
+    [0, 1, 1, 0, 0, 0, 0, 0], // 25%
+    [0, 1, 1, 1, 1, 0, 0, 0], // 50%
+    [1, 0, 0, 1, 1, 1, 1, 1], // 25% negated
+];
+
+/*
+ * Pulse channel ($4000-$4003, $4004-$4007)
+ *
+ * The two channels differ only in how the sweep unit negates:
+ * pulse 1 uses ones' complement, pulse 2 two's complement.
+ */
+pub struct Pulse {
+    ones_complement: bool,
+    envelope: Envelope,
+    length: LengthCounter,
+    duty: u8,
+    sequence: u8,
+    timer_period: u16,
+    timer: u16,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+}
+
+impl Pulse {
+    pub fn new(ones_complement: bool) -> Pulse {
+        Pulse {
+            ones_complement,
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+            duty: 0,
+            sequence: 0,
+            timer_period: 0,
+            timer: 0,
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_divider: 0,
+            sweep_reload: false,
+        }
+    }
+
+    /*
+     * reg is the register index within the channel (0-3)
+     */
+    pub fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                // DDLC VVVV
+                self.duty = data >> 6;
+                self.length.set_halt((data & 0b0010_0000) != 0);
+                self.envelope.write_control(data);
+            }
+            1 => {
+                // EPPP NSSS
+                self.sweep_enabled = (data & 0b1000_0000) != 0;
+                self.sweep_period = (data >> 4) & 0b0111;
+                self.sweep_negate = (data & 0b0000_1000) != 0;
+                self.sweep_shift = data & 0b0111;
+                self.sweep_reload = true;
+            }
+            2 => {
+                self.timer_period = (self.timer_period & 0x0700) | data as u16;
+            }
+            3 => {
+                // LLLL LTTT
+                self.timer_period = (self.timer_period & 0x00FF) | ((data as u16 & 0b0111) << 8);
+                self.length.load(data);
+                self.envelope.restart();
+                self.sequence = 0;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.length.set_enabled(enabled);
+    }
+
+    pub fn length_active(&self) -> bool {
+        self.length.active()
+    }
+
+    /* clocked every APU cycle (every other CPU cycle) */
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.sequence = (self.sequence + 1) & 7;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.timer_period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if self.sweep_negate {
+            let change = change + self.ones_complement as u16;
+            self.timer_period.saturating_sub(change)
+        } else {
+            self.timer_period + change
+        }
+    }
+
+    /*
+     * The sweep unit mutes the channel even when it is disabled
+     */
+    fn muted(&self) -> bool {
+        self.timer_period < 8 || self.sweep_target() > 0x07FF
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        self.envelope.save_state(w);
+        self.length.save_state(w);
+        w.write_u8(self.duty);
+        w.write_u8(self.sequence);
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer);
+        w.write_bool(self.sweep_enabled);
+        w.write_u8(self.sweep_period);
+        w.write_bool(self.sweep_negate);
+        w.write_u8(self.sweep_shift);
+        w.write_u8(self.sweep_divider);
+        w.write_bool(self.sweep_reload);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.envelope.load_state(r)?;
+        self.length.load_state(r)?;
+        self.duty = r.read_u8()? & 0b11;
+        self.sequence = r.read_u8()? & 7;
+        self.timer_period = r.read_u16()?;
+        self.timer = r.read_u16()?;
+        self.sweep_enabled = r.read_bool()?;
+        self.sweep_period = r.read_u8()?;
+        self.sweep_negate = r.read_bool()?;
+        self.sweep_shift = r.read_u8()? & 0b0111;
+        self.sweep_divider = r.read_u8()?;
+        self.sweep_reload = r.read_bool()?;
+        Ok(())
+    }
+
+    /* 0-15 */
+    pub fn output(&self) -> u8 {
+        if DUTY_TABLE[self.duty as usize][self.sequence as usize] == 0
+            || !self.length.active()
+            || self.muted()
+        {
+            0
+        } else {
+            self.envelope.volume()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sweep() {
+        let mut pulse1 = Pulse::new(true);
+        let mut pulse2 = Pulse::new(false);
+        for pulse in [&mut pulse1, &mut pulse2].iter_mut() {
+            pulse.write(2, 0x00);
+            pulse.write(3, 0x01); // period $100
+            pulse.write(1, 0b1000_1001); // enabled, period 0, negate, shift 1
+            pulse.clock_half_frame();
+        }
+
+        // pulse 1 subtracts one more than pulse 2
+        assert_eq!(pulse1.timer_period, 0x7F);
+        assert_eq!(pulse2.timer_period, 0x80);
+
+        // a target period above $7FF mutes the channel
+        pulse2.write(3, 0x07);
+        pulse2.write(2, 0xFF);
+        pulse2.write(1, 0b0000_0001);
+        assert!(pulse2.muted());
+        pulse2.clock_half_frame();
+        assert_eq!(pulse2.timer_period, 0x7FF);
+    }
+
+    #[test]
+    fn test_duty_and_volume() {
+        let mut pulse = Pulse::new(false);
+        pulse.set_enabled(true);
+        pulse.write(0, 0b1011_1010); // 50% duty, halt, constant volume 10
+        pulse.write(2, 0x08);
+        pulse.write(3, 0x00);
+
+        let mut out = Vec::new();
+        for _ in 0..8 {
+            out.push(pulse.output());
+            for _ in 0..=8 {
+                pulse.clock_timer();
+            }
+        }
+        assert_eq!(out, [0, 10, 10, 10, 10, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,190 @@
+use std::f64::consts::PI;
+
+/* NTSC CPU clock */
+pub const CPU_CLOCK_RATE: f64 = 1_789_773.0;
+
+/* taps of the band-limited step, and sub-sample positions it is tabulated for */
+const KERNEL_WIDTH: usize = 16;
+const KERNEL_PHASES: usize = 64;
+
+/* pass band as a fraction of the output Nyquist frequency */
+const CUTOFF: f64 = 0.9;
+
+/*
+ * Band-limited synthesis
+ *
+ * The mixed APU output only changes in steps. Instead of point sampling
+ * it at the host rate (which aliases), each step is added to the output
+ * as a band-limited step: a windowed sinc impulse is accumulated in a
+ * buffer of differences and integrated when the samples are read.
+ * The output lags by about KERNEL_WIDTH / 2 samples.
+ */
+pub struct Resampler {
+    sample_rate: u32,
+    /* output samples per CPU cycle */
+    factor: f64,
+    kernel: Vec<[f32; KERNEL_WIDTH]>,
+    deltas: Vec<f32>,
+    /* current time in output samples, relative to deltas[0] */
+    time: f64,
+    level: f32,
+    integrator: f32,
+    /* samples kept when the frontend does not read them */
+    capacity: usize,
+}
+
+impl Resampler {
+    pub fn new(sample_rate: u32) -> Resampler {
+        Resampler {
+            sample_rate,
+            factor: sample_rate as f64 / CPU_CLOCK_RATE,
+            kernel: Self::make_kernel(),
+            deltas: vec![0.0; KERNEL_WIDTH],
+            time: 0.0,
+            level: 0.0,
+            integrator: 0.0,
+            capacity: sample_rate as usize,
+        }
+    }
+
+    fn make_kernel() -> Vec<[f32; KERNEL_WIDTH]> {
+        let half = (KERNEL_WIDTH / 2) as f64;
+        (0..KERNEL_PHASES)
+            .map(|phase| {
+                let frac = phase as f64 / KERNEL_PHASES as f64;
+                let mut taps = [0.0; KERNEL_WIDTH];
+                for (k, tap) in taps.iter_mut().enumerate() {
+                    let x = k as f64 - frac - half + 1.0;
+                    let sinc = if x == 0.0 {
+                        1.0
+                    } else {
+                        (PI * CUTOFF * x).sin() / (PI * CUTOFF * x)
+                    };
+                    /* Blackman window over [-half, half] */
+                    let w = (x + half) / (2.0 * half);
+                    let window = 0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos();
+                    *tap = sinc * window.max(0.0);
+                }
+
+                /* every step must end up at exactly its amplitude */
+                let sum: f64 = taps.iter().sum();
+                let mut normalized = [0.0; KERNEL_WIDTH];
+                for (n, tap) in normalized.iter_mut().zip(taps.iter()) {
+                    *n = (tap / sum) as f32;
+                }
+                normalized
+            })
+            .collect()
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /*
+     * Feed the mixer output of one CPU cycle
+     */
+    pub fn push(&mut self, level: f32) {
+        let delta = level - self.level;
+        if delta != 0.0 {
+            self.level = level;
+            self.add_step(delta);
+        }
+        self.time += self.factor;
+
+        if self.available() > self.capacity {
+            let excess = self.available() - self.capacity;
+            self.drain(excess, |_| {});
+        }
+    }
+
+    fn add_step(&mut self, delta: f32) {
+        let start = self.time as usize;
+        let phase = ((self.time - start as f64) * KERNEL_PHASES as f64) as usize;
+        let taps = &self.kernel[phase.min(KERNEL_PHASES - 1)];
+
+        let end = start + KERNEL_WIDTH;
+        if self.deltas.len() < end {
+            self.deltas.resize(end, 0.0);
+        }
+        for (d, tap) in self.deltas[start..end].iter_mut().zip(taps.iter()) {
+            *d += delta * tap;
+        }
+    }
+
+    /*
+     * Number of samples that no future step can change anymore
+     */
+    pub fn available(&self) -> usize {
+        self.time as usize
+    }
+
+    /*
+     * Integrate and remove up to n finished samples, passing each to f
+     */
+    pub fn drain<F: FnMut(f32)>(&mut self, n: usize, mut f: F) -> usize {
+        let n = n.min(self.available());
+        /* no steps since the last read: the samples are all flat */
+        if self.deltas.len() < n + KERNEL_WIDTH {
+            self.deltas.resize(n + KERNEL_WIDTH, 0.0);
+        }
+        for d in self.deltas.drain(..n) {
+            self.integrator += d;
+            f(self.integrator);
+        }
+        self.time -= n as f64;
+        n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(resampler: &mut Resampler) -> Vec<f32> {
+        let mut out = Vec::new();
+        let n = resampler.available();
+        resampler.drain(n, |s| out.push(s));
+        out
+    }
+
+    #[test]
+    fn test_sample_count() {
+        let mut resampler = Resampler::new(44100);
+        for _ in 0..CPU_CLOCK_RATE as usize / 10 {
+            resampler.push(0.0);
+        }
+        let n = read_all(&mut resampler).len();
+        assert!(n == 4409 || n == 4410);
+    }
+
+    #[test]
+    fn test_step_response() {
+        let mut resampler = Resampler::new(48000);
+        for _ in 0..1000 {
+            resampler.push(0.0);
+        }
+        for _ in 0..1000 {
+            resampler.push(0.5);
+        }
+
+        let out = read_all(&mut resampler);
+        // settles to the amplitude of the step
+        assert!((out.last().unwrap() - 0.5).abs() < 0.0001);
+        // with only a small overshoot (Gibbs phenomenon)
+        assert!(out.iter().all(|&s| s > -0.05 && s < 0.55));
+    }
+
+    #[test]
+    fn test_no_aliasing() {
+        // a square wave far above the output Nyquist frequency
+        let mut resampler = Resampler::new(44100);
+        for i in 0..CPU_CLOCK_RATE as usize / 10 {
+            resampler.push(if (i / 20) % 2 == 0 { 1.0 } else { 0.0 });
+        }
+
+        // is filtered down to (almost) DC
+        let out = read_all(&mut resampler);
+        assert!(out[100..].iter().all(|&s| (s - 0.5).abs() < 0.1));
+    }
+}
@@ -0,0 +1,124 @@
+use super::length_counter::LengthCounter;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+/*
+ * Triangle channel ($4008-$400B)
+ *
+ * The sequencer only advances while both the linear counter and the
+ * length counter are non-zero; otherwise it holds its current level.
+ */
+pub struct Triangle {
+    length: LengthCounter,
+    control: bool, // also the length counter halt flag
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+    sequence: u8,
+    timer_period: u16,
+    timer: u16,
+}
+
+impl Triangle {
+    pub fn new() -> Triangle {
+        Triangle {
+            length: LengthCounter::new(),
+            control: false,
+            linear_reload_value: 0,
+            linear_counter: 0,
+            linear_reload: false,
+            sequence: 0,
+            timer_period: 0,
+            timer: 0,
+        }
+    }
+
+    pub fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                // CRRR RRRR
+                self.control = (data & 0b1000_0000) != 0;
+                self.length.set_halt(self.control);
+                self.linear_reload_value = data & 0b0111_1111;
+            }
+            2 => {
+                self.timer_period = (self.timer_period & 0x0700) | data as u16;
+            }
+            3 => {
+                // LLLL LTTT
+                self.timer_period = (self.timer_period & 0x00FF) | ((data as u16 & 0b0111) << 8);
+                self.length.load(data);
+                self.linear_reload = true;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.length.set_enabled(enabled);
+    }
+
+    pub fn length_active(&self) -> bool {
+        self.length.active()
+    }
+
+    /* clocked every CPU cycle */
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.linear_counter > 0 && self.length.active() {
+                self.sequence = (self.sequence + 1) & 31;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        self.length.save_state(w);
+        w.write_bool(self.control);
+        w.write_u8(self.linear_reload_value);
+        w.write_u8(self.linear_counter);
+        w.write_bool(self.linear_reload);
+        w.write_u8(self.sequence);
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.length.load_state(r)?;
+        self.control = r.read_bool()?;
+        self.linear_reload_value = r.read_u8()?;
+        self.linear_counter = r.read_u8()?;
+        self.linear_reload = r.read_bool()?;
+        self.sequence = r.read_u8()? & 31;
+        self.timer_period = r.read_u16()?;
+        self.timer = r.read_u16()?;
+        Ok(())
+    }
+
+    /* 0-15 */
+    pub fn output(&self) -> u8 {
+        SEQUENCE[self.sequence as usize]
+    }
+}
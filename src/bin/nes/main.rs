@@ -0,0 +1,117 @@
+#[cfg(feature = "opencv")]
+mod window;
+
+use nes::Nes;
+use std::io;
+
+fn usage(prog: &str) {
+    println!(
+        "Usage: {} [--wav FILE [--wav-channels]] [--load-slot N] [--frames N] [--trace] NES",
+        prog
+    );
+    println!("  --wav FILE      write the audio output to FILE");
+    println!("  --wav-channels  also write each APU channel next to FILE");
+    println!("                  (out.wav: out.pulse1.wav, out.pulse2.wav, ... out.dmc.wav)");
+    println!("  --load-slot N   start from save slot N (0-9)");
+    println!("  --frames N      stop after N frames");
+    println!("  --trace         print every CPU instruction in nestest.log format");
+    println!();
+    println!("Keys: 0-9 select a save slot, S saves to it, L loads it");
+    println!("      hold R to rewind");
+}
+
+fn nes_main(args: Vec<String>) -> io::Result<i32> {
+    let mut rom_path = None;
+    let mut wav_path = None;
+    let mut wav_channels = false;
+    let mut load_slot = None;
+    let mut trace = false;
+    let mut frames = None;
+
+    let mut opts = args.iter().skip(1);
+    while let Some(arg) = opts.next() {
+        match arg.as_str() {
+            "--wav" => match opts.next() {
+                Some(path) => wav_path = Some(path),
+                None => {
+                    usage(&args[0]);
+                    return Ok(-1);
+                }
+            },
+            "--wav-channels" => wav_channels = true,
+            "--load-slot" => match opts.next().and_then(|n| n.parse::<u8>().ok()) {
+                Some(slot) if slot <= 9 => load_slot = Some(slot),
+                _ => {
+                    usage(&args[0]);
+                    return Ok(-1);
+                }
+            },
+            "--frames" => match opts.next().and_then(|n| n.parse::<u64>().ok()) {
+                Some(n) => frames = Some(n),
+                None => {
+                    usage(&args[0]);
+                    return Ok(-1);
+                }
+            },
+            "--trace" => trace = true,
+            _ if rom_path.is_none() && !arg.starts_with("--") => rom_path = Some(arg),
+            _ => {
+                usage(&args[0]);
+                return Ok(-1);
+            }
+        }
+    }
+
+    let rom_path = match rom_path {
+        Some(path) => path,
+        None => {
+            usage(&args[0]);
+            return Ok(-1);
+        }
+    };
+
+    let mut nes = Nes::load(rom_path)?;
+    if let Some(path) = wav_path {
+        nes.record_wav(path, wav_channels)?;
+    }
+    if trace {
+        nes.set_tracer(Some(Box::new(|line: &str| println!("{}", line))));
+    }
+    if let Some(slot) = load_slot {
+        nes.load_slot(slot)?;
+    }
+    let exit_code = run(&mut nes, load_slot.unwrap_or(0), frames)?;
+    nes.finish_wav()?;
+    Ok(exit_code)
+}
+
+#[cfg(feature = "opencv")]
+fn run(nes: &mut Nes, slot: u8, frames: Option<u64>) -> io::Result<i32> {
+    // rewind: a state every other frame, up to 10 seconds back
+    nes.set_rewind(2, 300);
+    window::run(nes, slot, frames)?;
+    Ok(0)
+}
+
+/* without a window: run headless, e.g. to record the audio */
+#[cfg(not(feature = "opencv"))]
+fn run(nes: &mut Nes, _slot: u8, frames: Option<u64>) -> io::Result<i32> {
+    eprintln!("No display (build with `--features opencv` for a window): running headless");
+    let mut frame = 0;
+    while frames != Some(frame) {
+        nes.run_frame();
+        nes.write_wav()?;
+        frame += 1;
+    }
+    Ok(0)
+}
+
+fn main() {
+    let args = std::env::args().collect();
+    let exit_code = nes_main(args).unwrap_or_else(|e| {
+        eprintln!("FATAL: {}", e);
+        -1
+    });
+
+    std::process::exit(exit_code);
+}
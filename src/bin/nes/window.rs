@@ -0,0 +1,106 @@
+use nes::{Nes, VideoSink, SCREEN_HEIGHT, SCREEN_WIDTH};
+use std::io;
+
+const WINDOW_TITLE: &str = "Tapioca-NES";
+
+/*
+ * The OpenCV window
+ */
+struct OpenCvWindow {
+    frame: opencv::core::Mat,
+}
+
+impl OpenCvWindow {
+    fn new() -> OpenCvWindow {
+        opencv::highgui::start_window_thread().unwrap();
+        OpenCvWindow {
+            frame: unsafe {
+                opencv::core::Mat::new_rows_cols(
+                    SCREEN_HEIGHT as i32,
+                    SCREEN_WIDTH as i32,
+                    opencv::core::CV_8UC3,
+                )
+                .unwrap()
+            },
+        }
+    }
+
+    /*
+     * The key pressed in the window during the last `delay` ms, if any
+     */
+    fn wait_key(delay: i32) -> Option<char> {
+        match opencv::highgui::wait_key(delay).unwrap() {
+            -1 => None,
+            key => std::char::from_u32(key as u32).map(|key| key.to_ascii_lowercase()),
+        }
+    }
+}
+
+impl VideoSink for OpenCvWindow {
+    fn present(&mut self, rgb: &[u8]) {
+        for (i, px) in rgb.chunks(3).enumerate() {
+            let (y, x) = ((i / SCREEN_WIDTH) as i32, (i % SCREEN_WIDTH) as i32);
+            // OpenCV wants BGR
+            *self.frame.at_2d_mut(y, x).unwrap() = opencv::core::Vec3::from([px[2], px[1], px[0]]);
+        }
+
+        let mut screen = opencv::core::Mat::new().unwrap();
+        opencv::imgproc::resize(
+            &self.frame,
+            &mut screen,
+            opencv::core::Size::new(1024, 1024),
+            0.0,
+            0.0,
+            0,
+        )
+        .unwrap();
+
+        opencv::highgui::imshow(WINDOW_TITLE, &screen).unwrap();
+    }
+}
+
+/*
+ * Run in the OpenCV window for `frames` frames, or until the process is
+ * killed.
+ *
+ * Keys: 0-9 select a save slot, S saves to it and L loads it.
+ * Holding R rewinds one frame per key repeat.
+ */
+pub fn run(nes: &mut Nes, mut slot: u8, frames: Option<u64>) -> io::Result<()> {
+    nes.set_video_sink(Box::new(OpenCvWindow::new()));
+
+    let mut frame = 0;
+    let mut rewound = false;
+    while frames != Some(frame) {
+        if !rewound {
+            nes.run_frame();
+            frame += 1;
+        }
+        nes.write_wav()?;
+
+        rewound = false;
+        let result = match OpenCvWindow::wait_key(1) {
+            Some('r') => {
+                // the rewound picture is already shown
+                rewound = nes.rewind_frame();
+                Ok(())
+            }
+            Some(key @ '0'..='9') => {
+                slot = key as u8 - b'0';
+                println!("Save slot {}", slot);
+                Ok(())
+            }
+            Some('s') => nes
+                .save_slot(slot)
+                .map(|_| println!("Saved state to slot {}", slot)),
+            Some('l') => nes
+                .load_slot(slot)
+                .map(|_| println!("Loaded state from slot {}", slot)),
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            eprintln!("Save slot {}: {}", slot, e);
+        }
+    }
+    Ok(())
+}
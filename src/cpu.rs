@@ -1,10 +1,16 @@
 use crate::cpu_bus;
+use crate::savestate::{StateError, StateReader, StateWriter};
 
 pub struct Cpu {
     regs: Registers,
     bus: cpu_bus::CpuBus,
+    cycles: u64, // total CPU cycles elapsed since power on
+    tracer: Option<Tracer>, // gets trace() before every instruction
 }
 
+/// Receives one nestest.log style line per executed instruction
+pub type Tracer = Box<dyn FnMut(&str)>;
+
 struct Registers {
     pub a: u8,   // accumlator register
     pub x: u8,   // index register
@@ -26,7 +32,7 @@ impl Default for Registers {
                 negative: false,
                 overflow: false,
                 reserved: true,
-                break_mode: true,
+                break_mode: false,
                 decimal: false,
                 interrupt: true,
                 zero: false,
@@ -57,7 +63,7 @@ impl From<Status> for u8 {
             | ((v.decimal as u8) << 3)
             | ((v.interrupt as u8) << 2)
             | ((v.zero as u8) << 1)
-            | ((v.carry as u8) << 0)
+            | (v.carry as u8)
     }
 }
 
@@ -79,24 +85,25 @@ impl From<u8> for Status {
 #[rustfmt::skip]
 const CYCLE: [u8; 256] = [
      /*0x00*/ 7, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 4, 4, 6, 6,
-     /*0x10*/ 2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 6, 7,
+     /*0x10*/ 2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
      /*0x20*/ 6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 4, 4, 6, 6,
-     /*0x30*/ 2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 6, 7,
+     /*0x30*/ 2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
      /*0x40*/ 6, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 3, 4, 6, 6,
-     /*0x50*/ 2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 6, 7,
+     /*0x50*/ 2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
      /*0x60*/ 6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 5, 4, 6, 6,
-     /*0x70*/ 2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 6, 7,
+     /*0x70*/ 2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
      /*0x80*/ 2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,
-     /*0x90*/ 2, 6, 2, 6, 4, 4, 4, 4, 2, 4, 2, 5, 5, 4, 5, 5,
+     /*0x90*/ 2, 6, 2, 6, 4, 4, 4, 4, 2, 5, 2, 5, 5, 5, 5, 5,
      /*0xA0*/ 2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,
      /*0xB0*/ 2, 5, 2, 5, 4, 4, 4, 4, 2, 4, 2, 4, 4, 4, 4, 4,
      /*0xC0*/ 2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,
      /*0xD0*/ 2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
-     /*0xE0*/ 2, 6, 3, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,
+     /*0xE0*/ 2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,
      /*0xF0*/ 2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
 ];
 
 #[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
 enum Instruction {
     // A: Accumlator M: fetched memory data C: The flag to set by an instruction.
     ADC, // Add M to A with C: A += M + C
@@ -155,8 +162,51 @@ enum Instruction {
     PHP, // Push P on stack
     PLP, // Pull P from stack
     NOP, // No operation
-    //NOPI,
-    //NOPD,
+    /* Instructions below are unofficial */
+    LAX, // Load A and X from M
+    SAX, // Store A & X to M
+    DCP, // DEC M then CMP
+    ISC, // INC M then SBC
+    SLO, // ASL M then ORA
+    RLA, // ROL M then AND
+    SRE, // LSR M then EOR
+    RRA, // ROR M then ADC
+    ANC, // AND M with A then C = bit 7 of A
+    ALR, // AND M with A then LSR A
+    ARR, // AND M with A then ROR A: C = bit 6 of A, V = bit 6 ^ bit 5 of A
+    AXS, // X = (A & X) - M without borrow, setting flags like CMP
+    LAS, // A = X = S = M & S
+    ANE, // A = (A | magic) & X & M (unstable)
+    LXA, // A = X = (A | magic) & M (unstable)
+    SHA, // Store A & X & (high byte of ADDR + 1) to M (unstable)
+    SHX, // Store X & (high byte of ADDR + 1) to M (unstable)
+    SHY, // Store Y & (high byte of ADDR + 1) to M (unstable)
+    TAS, // S = A & X then store S & (high byte of ADDR + 1) to M (unstable)
+    JAM, // Halt the CPU
+}
+
+impl Instruction {
+    /*
+     * Whether the instruction takes one more cycle
+     * when its effective address crosses a page boundary
+     */
+    fn has_page_cross_penalty(&self) -> bool {
+        matches!(
+            self,
+            Instruction::ADC
+                | Instruction::SBC
+                | Instruction::AND
+                | Instruction::ORA
+                | Instruction::EOR
+                | Instruction::CMP
+                | Instruction::LDA
+                | Instruction::LDX
+                | Instruction::LDY
+                | Instruction::LAX
+                | Instruction::LAS
+                | Instruction::NOP
+        )
+    }
 }
 
 #[derive(Debug)]
@@ -176,6 +226,12 @@ enum Addressing {
     Indirect,
 }
 
+/*
+ * The "magic" constant ANE and LXA OR into A before the AND.
+ * It depends on the chip and temperature; 0xEE is the common value.
+ */
+const UNSTABLE_MAGIC: u8 = 0xEE;
+
 enum ReadSize {
     Word,
     Byte,
@@ -186,22 +242,52 @@ impl Cpu {
         Cpu {
             regs: Default::default(),
             bus: cpu_bus,
+            cycles: 0,
+            tracer: None,
         }
     }
 
-    pub fn reset(&mut self) {
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn bus(&self) -> &cpu_bus::CpuBus {
+        &self.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut cpu_bus::CpuBus {
+        &mut self.bus
+    }
+
+    // counts the cycles the CPU was halted for by DMC fetches
+    pub fn stall(&mut self, cycles: usize) {
+        self.cycles += cycles as u64;
+    }
+
+    // returns the number of cycles the reset sequence took
+    pub fn reset(&mut self) -> usize {
         self.regs = Default::default();
         self.regs.pc = self.read(0xFFFC, ReadSize::Word);
+        self.cycles += 7;
+        7
     }
 
     pub fn nmi_handler(&mut self) {
-        self.regs.p.break_mode = false;
         self.push(((self.regs.pc & 0xFF00) >> 8) as u8);
         self.push((self.regs.pc & 0x00FF) as u8);
-        self.push_status();
+        self.push_status(false);
         self.regs.p.interrupt = true;
         self.regs.pc = self.read(0xFFFA, ReadSize::Word);
-        println!("NMIdesu {:x}", self.regs.pc);
+        self.cycles += 7;
+    }
+
+    pub fn irq_handler(&mut self) {
+        self.push(((self.regs.pc & 0xFF00) >> 8) as u8);
+        self.push((self.regs.pc & 0x00FF) as u8);
+        self.push_status(false);
+        self.regs.p.interrupt = true;
+        self.regs.pc = self.read(0xFFFE, ReadSize::Word);
+        self.cycles += 7;
     }
 
     fn read(&mut self, addr: u16, size: ReadSize) -> u16 {
@@ -220,21 +306,26 @@ impl Cpu {
 
     fn push(&mut self, data: u8) {
         self.bus.write_by_cpu(self.regs.sp, data);
-        self.regs.sp -= 1;
+        self.regs.sp = 0x0100 | (self.regs.sp.wrapping_sub(1) & 0xFF);
     }
 
-    fn push_status(&mut self) {
-        self.push(self.regs.p.clone().into());
+    // the B flag only exists on the stack: set by PHP/BRK, cleared by IRQ/NMI
+    fn push_status(&mut self, break_mode: bool) {
+        let mut status = self.regs.p.clone();
+        status.reserved = true;
+        status.break_mode = break_mode;
+        self.push(status.into());
     }
 
     fn pop_status(&mut self) {
         self.regs.p = self.pop().into();
+        self.regs.p.reserved = true;
+        self.regs.p.break_mode = false;
     }
 
     fn pop(&mut self) -> u8 {
-        self.regs.sp += 1;
-        let data = self.bus.read_by_cpu(self.regs.sp);
-        data
+        self.regs.sp = 0x0100 | (self.regs.sp.wrapping_add(1) & 0xFF);
+        self.bus.read_by_cpu(self.regs.sp)
     }
 
     // fetch opcode (8-bit)
@@ -249,100 +340,193 @@ impl Cpu {
         let lower_byte = self.fetch();
         let upper_byte = self.fetch();
         //println!("fetch {:x} {:x}", upper_byte, lower_byte);
-        ((upper_byte as u16) << 8) | lower_byte as u16
+        (upper_byte << 8) | lower_byte
     }
 
-    fn fetch_operand(&mut self, addressing: &Addressing) -> u16 {
+    // returns the operand and whether indexing crossed a page boundary
+    fn fetch_operand(&mut self, addressing: &Addressing) -> (u16, bool) {
         match addressing {
-            Addressing::Accumlator => 0,
-            Addressing::Immediate => self.fetch(),
-            Addressing::Absolute => self.fetch_addr(),
-            Addressing::ZeroPage => self.fetch() as u16,
-            Addressing::ZeroPageX => (self.fetch() as u16 + self.regs.x as u16) & 0xFF,
-            Addressing::ZeroPageY => (self.fetch() as u16 + self.regs.y as u16) & 0xFF,
-            Addressing::AbsoluteX => self.fetch_addr() + self.regs.x as u16,
-            Addressing::AbsoluteY => self.fetch_addr() + self.regs.y as u16,
-            Addressing::Implied => 0,
+            Addressing::Accumlator => (0, false),
+            Addressing::Immediate => (self.fetch(), false),
+            Addressing::Absolute => (self.fetch_addr(), false),
+            Addressing::ZeroPage => (self.fetch(), false),
+            Addressing::ZeroPageX => ((self.fetch() + self.regs.x as u16) & 0xFF, false),
+            Addressing::ZeroPageY => ((self.fetch() + self.regs.y as u16) & 0xFF, false),
+            Addressing::AbsoluteX => {
+                let base = self.fetch_addr();
+                let addr = base.wrapping_add(self.regs.x as u16);
+                (addr, Self::page_crossed(base, addr))
+            }
+            Addressing::AbsoluteY => {
+                let base = self.fetch_addr();
+                let addr = base.wrapping_add(self.regs.y as u16);
+                (addr, Self::page_crossed(base, addr))
+            }
+            Addressing::Implied => (0, false),
             Addressing::Relative => {
-                let base = self.fetch() as u16;
-                if base < 0x80 {
-                    base + self.regs.pc
-                } else {
-                    base + self.regs.pc - 256
-                }
+                let offset = self.fetch() as u8 as i8;
+                (self.regs.pc.wrapping_add(offset as u16), false)
             }
             Addressing::Indirect => {
                 let addr = self.fetch_addr();
+                // the upper byte is fetched without carrying into the page
+                let upper_addr = (addr & 0xFF00) | (addr.wrapping_add(1) & 0x00FF);
                 let lower_byte = self.read(addr, ReadSize::Byte);
-                let upper_byte = self.read(addr + 1, ReadSize::Byte);
-                (upper_byte << 8) | lower_byte
+                let upper_byte = self.read(upper_addr, ReadSize::Byte);
+                ((upper_byte << 8) | lower_byte, false)
             }
             Addressing::IndirectX => {
                 let addr = (self.fetch() + self.regs.x as u16) & 0xFF;
                 let lower_byte = self.read(addr, ReadSize::Byte);
-                let upper_byte = self.read(addr + 1, ReadSize::Byte);
-                (upper_byte << 8) | lower_byte
+                let upper_byte = self.read((addr + 1) & 0xFF, ReadSize::Byte);
+                ((upper_byte << 8) | lower_byte, false)
             }
             Addressing::IndirectY => {
-                let addr = (self.fetch() as u16) & 0xFF;
+                let addr = self.fetch() & 0xFF;
                 let lower_byte = self.read(addr, ReadSize::Byte);
-                let upper_byte = self.read(addr + 1, ReadSize::Byte);
-                (upper_byte << 8) | lower_byte + self.regs.y as u16
+                let upper_byte = self.read((addr + 1) & 0xFF, ReadSize::Byte);
+                let base = (upper_byte << 8) | lower_byte;
+                let addr = base.wrapping_add(self.regs.y as u16);
+                (addr, Self::page_crossed(base, addr))
             }
         }
     }
 
+    fn page_crossed(a: u16, b: u16) -> bool {
+        (a & 0xFF00) != (b & 0xFF00)
+    }
+
+    // takes the branch if `cond` holds, consuming 1 more cycle (2 more across pages)
+    fn branch(&mut self, cond: bool, addr: u16) {
+        if !cond {
+            return;
+        }
+
+        self.cycles += if Self::page_crossed(self.regs.pc, addr) {
+            2
+        } else {
+            1
+        };
+        self.regs.pc = addr;
+    }
+
     fn check_negative(&self, register: &u8) -> bool {
         (register & (1 << 7)) >> 7 == 1
     }
 
-    fn checked_add(reg: u8, operand: u8, carry: bool) -> (u8, bool) {
-        let carry = if carry { 1 } else { 0 };
-        let result = (reg as i8).checked_add(operand as i8 + carry);
+    fn set_zero_negative(&mut self, data: u8) {
+        self.regs.p.zero = data == 0;
+        self.regs.p.negative = self.check_negative(&data);
+    }
 
-        match result {
-            Some(res) => (res as u8, false),
-            None => ((reg as i16 + operand as i16 + carry as i16) as u8, true),
+    // immediate operands are the data itself, the others are addresses of the data
+    fn read_data(&mut self, addressing: &Addressing, operand: u16) -> u8 {
+        match addressing {
+            Addressing::Immediate => operand as u8,
+            _ => self.read(operand, ReadSize::Byte) as u8,
         }
     }
 
-    fn checked_sub(reg: u8, operand: u8, carry: bool) -> (u8, bool) {
-        let carry = if carry { 1 } else { 0 };
-        let result = (reg as i8).checked_sub(operand as i8 + carry);
+    // A += M + C, updating C, V, N and Z (SBC adds the complement of M)
+    fn add_with_carry(&mut self, data: u8) {
+        let sum = self.regs.a as u16 + data as u16 + self.regs.p.carry as u16;
+        let result = sum as u8;
+        self.regs.p.carry = sum > 0xFF;
+        self.regs.p.overflow = ((self.regs.a ^ result) & (data ^ result) & 0x80) != 0;
+        self.regs.a = result;
+        self.set_zero_negative(result);
+    }
+
+    fn compare(&mut self, reg: u8, data: u8) {
+        self.regs.p.carry = reg >= data;
+        self.set_zero_negative(reg.wrapping_sub(data));
+    }
+
+    fn shift_left(&mut self, data: u8) -> u8 {
+        self.regs.p.carry = (data & 0x80) != 0;
+        let result = data << 1;
+        self.set_zero_negative(result);
+        result
+    }
+
+    fn shift_right(&mut self, data: u8) -> u8 {
+        self.regs.p.carry = (data & 0x01) != 0;
+        let result = data >> 1;
+        self.set_zero_negative(result);
+        result
+    }
+
+    fn rotate_left(&mut self, data: u8) -> u8 {
+        let result = (data << 1) | self.regs.p.carry as u8;
+        self.regs.p.carry = (data & 0x80) != 0;
+        self.set_zero_negative(result);
+        result
+    }
+
+    fn rotate_right(&mut self, data: u8) -> u8 {
+        let result = (data >> 1) | ((self.regs.p.carry as u8) << 7);
+        self.regs.p.carry = (data & 0x01) != 0;
+        self.set_zero_negative(result);
+        result
+    }
 
-        match result {
-            Some(res) => (res as u8, false),
-            None => ((reg as i16 - operand as i16 - carry as i16) as u8, true),
+    /*
+     * Apply `op` to A (accumlator addressing) or to M (read-modify-write)
+     * and return the result
+     */
+    fn modify(&mut self, addressing: &Addressing, operand: u16, op: fn(&mut Self, u8) -> u8) -> u8 {
+        match addressing {
+            Addressing::Accumlator => {
+                let data = self.regs.a;
+                self.regs.a = op(self, data);
+                self.regs.a
+            }
+            _ => {
+                let data = self.read(operand, ReadSize::Byte) as u8;
+                let result = op(self, data);
+                self.bus.write_by_cpu(operand, result);
+                result
+            }
         }
     }
 
+    fn increment(&mut self, data: u8) -> u8 {
+        let result = data.wrapping_add(1);
+        self.set_zero_negative(result);
+        result
+    }
+
+    fn decrement(&mut self, data: u8) -> u8 {
+        let result = data.wrapping_sub(1);
+        self.set_zero_negative(result);
+        result
+    }
+
+    /*
+     * SHA, SHX, SHY and TAS store `data & (H + 1)` where H is the high byte
+     * of the base address. When indexing crosses a page, the high byte of
+     * the effective address gets corrupted with the stored value too.
+     */
+    fn store_and_high(&mut self, operand: u16, index: u8, data: u8) {
+        let base = operand.wrapping_sub(index as u16);
+        let result = data & ((base >> 8) as u8).wrapping_add(1);
+        let addr = if Self::page_crossed(base, operand) {
+            ((result as u16) << 8) | (operand & 0x00FF)
+        } else {
+            operand
+        };
+        self.bus.write_by_cpu(addr, result);
+    }
+
     fn exec(&mut self, instruction: &Instruction, addressing: &Addressing, operand: u16) {
         match instruction {
             Instruction::ADC => {
-                let data = match addressing {
-                    Addressing::Immediate => operand as u8,
-                    _ => self.read(operand, ReadSize::Byte) as u8,
-                };
-
-                let (reg, overflow) = Self::checked_add(self.regs.a, data, self.regs.p.carry);
-                self.regs.a = reg;
-                self.regs.p.overflow = overflow;
-
-                self.regs.p.negative = self.check_negative(&self.regs.a);
-                self.regs.p.zero = self.regs.a == 0;
+                let data = self.read_data(addressing, operand);
+                self.add_with_carry(data);
             }
             Instruction::SBC => {
-                let data = match addressing {
-                    Addressing::Immediate => operand as u8,
-                    _ => self.read(operand, ReadSize::Byte) as u8,
-                };
-
-                let (reg, overflow) = Self::checked_sub(self.regs.a, data, self.regs.p.carry);
-                self.regs.a = reg;
-                self.regs.p.overflow = overflow;
-
-                self.regs.p.negative = self.check_negative(&self.regs.a);
-                self.regs.p.zero = self.regs.a == 0;
+                let data = self.read_data(addressing, operand);
+                self.add_with_carry(!data);
             }
             Instruction::AND => {
                 //print!("AND ");
@@ -394,135 +578,51 @@ impl Cpu {
             }
             Instruction::ASL => {
                 //print!("ASL ");
-                match addressing {
-                    Addressing::Accumlator => {
-                        self.regs.p.carry = ((self.regs.a & (1 << 7)) >> 7) != 0;
-                        self.regs.a = ((self.regs.a as i8) << 1) as u8;
-                    }
-                    _ => {
-                        self.regs.p.carry = (((operand as u8) & (1 << 7)) >> 7) != 0;
-                        self.regs.a = ((operand as i8) << 1) as u8;
-                    }
-                }
-                self.regs.p.zero = self.regs.a == 0;
-                self.regs.p.negative = self.check_negative(&self.regs.a);
+                self.modify(addressing, operand, Self::shift_left);
             }
             Instruction::LSR => {
                 //print!("LSR ");
-                match addressing {
-                    Addressing::Accumlator => {
-                        self.regs.p.carry = (self.regs.a & 1) != 0;
-                        self.regs.a = self.regs.a >> 1;
-                    }
-                    _ => {
-                        self.regs.p.carry = (operand & 1) != 0;
-                        self.regs.a = (operand as u8) >> 1;
-                    }
-                }
-                self.regs.p.zero = self.regs.a == 0;
-                self.regs.p.negative = self.check_negative(&self.regs.a);
+                self.modify(addressing, operand, Self::shift_right);
             }
             Instruction::ROL => {
                 //print!("ROL");
-                match addressing {
-                    Addressing::Accumlator => {
-                        self.regs.p.carry = ((self.regs.a & (1 << 7)) >> 7) != 0;
-                        self.regs.a = self.regs.a.rotate_left(1);
-                    }
-                    _ => {
-                        self.regs.p.carry = (((operand as u8) & (1 << 7)) >> 7) != 0;
-                        self.regs.a = (operand as u8).rotate_left(1);
-                    }
-                }
-                self.regs.p.zero = self.regs.a == 0;
-                self.regs.p.negative = self.check_negative(&self.regs.a);
+                self.modify(addressing, operand, Self::rotate_left);
             }
             Instruction::ROR => {
                 //print!("ROR ");
-                match addressing {
-                    Addressing::Accumlator => {
-                        self.regs.p.carry = (self.regs.a & 1) != 0;
-                        self.regs.a = self.regs.a.rotate_right(1);
-                    }
-                    _ => {
-                        self.regs.p.carry = (operand & 1) != 0;
-                        self.regs.a = (operand as u8).rotate_right(1);
-                    }
-                }
-                self.regs.p.zero = self.regs.a == 0;
-                self.regs.p.negative = self.check_negative(&self.regs.a);
+                self.modify(addressing, operand, Self::rotate_right);
             }
             Instruction::BCC => {
                 //print!("BCC ");
-                self.regs.pc = if !self.regs.p.carry {
-                    //print!("{:x} -> pc:{:x}", operand, self.regs.pc);
-                    operand
-                } else {
-                    self.regs.pc
-                };
+                self.branch(!self.regs.p.carry, operand);
             }
             Instruction::BCS => {
                 //print!("BCS ");
-                self.regs.pc = if self.regs.p.carry {
-                    //print!("{:x} -> pc:{:x}", operand, self.regs.pc);
-                    operand
-                } else {
-                    self.regs.pc
-                };
+                self.branch(self.regs.p.carry, operand);
             }
             Instruction::BEQ => {
                 //print!("BEQ ");
-                self.regs.pc = if self.regs.p.zero {
-                    //print!("{:x} -> pc:{:x}", operand, self.regs.pc);
-                    operand
-                } else {
-                    self.regs.pc
-                };
+                self.branch(self.regs.p.zero, operand);
             }
             Instruction::BNE => {
                 //println!("BNE ${:x} ", operand);
-                self.regs.pc = if !self.regs.p.zero {
-                    //print!("{:x} -> pc:{:x}", operand, self.regs.pc);
-                    operand
-                } else {
-                    self.regs.pc
-                };
+                self.branch(!self.regs.p.zero, operand);
             }
             Instruction::BVC => {
                 //print!("BVC ");
-                self.regs.pc = if !self.regs.p.overflow {
-                    //print!("{:x} -> pc:{:x}", operand, self.regs.pc);
-                    operand
-                } else {
-                    self.regs.pc
-                };
+                self.branch(!self.regs.p.overflow, operand);
             }
             Instruction::BVS => {
                 //print!("BVS ");
-                self.regs.pc = if self.regs.p.overflow {
-                    //print!("{:x} -> pc:{:x}", operand, self.regs.pc);
-                    operand
-                } else {
-                    self.regs.pc
-                };
+                self.branch(self.regs.p.overflow, operand);
             }
             Instruction::BPL => {
                 //print!("BPL ");
-                self.regs.pc = if !self.regs.p.negative {
-                    //print!("{:x} -> pc:{:x}", operand, self.regs.pc);
-                    operand
-                } else {
-                    self.regs.pc
-                };
+                self.branch(!self.regs.p.negative, operand);
             }
             Instruction::BMI => {
                 //print!("BMI ");
-                self.regs.pc = if self.regs.p.negative {
-                    //print!("{:x} -> pc:{:x}", operand, self.regs.pc);
-                    operand
-                } else {
-                    self.regs.pc
-                };
+                self.branch(self.regs.p.negative, operand);
             }
             Instruction::BIT => {
                 let target = self.read(operand, ReadSize::Byte) as u8;
@@ -537,8 +637,10 @@ impl Cpu {
                 //print!("JMP {:x} -> pc:{:x}", operand, self.regs.pc);
             }
             Instruction::JSR => {
-                self.push(((self.regs.pc & 0xFF00) >> 8) as u8);
-                self.push((self.regs.pc & 0xFF) as u8);
+                // the pushed address points to the last byte of JSR
+                let ret = self.regs.pc.wrapping_sub(1);
+                self.push(((ret & 0xFF00) >> 8) as u8);
+                self.push((ret & 0xFF) as u8);
                 //self.print_stack();
                 self.regs.pc = operand;
                 //print!("JSR");
@@ -547,26 +649,18 @@ impl Cpu {
                 //self.print_stack();
                 let lower = self.pop() as u16;
                 let upper = self.pop() as u16;
-                self.regs.pc = (upper << 8) | lower;
+                self.regs.pc = ((upper << 8) | lower).wrapping_add(1);
                 //print!("RTS -> {:x}", self.regs.pc);
             }
             Instruction::BRK => {
                 //print!("BRK");
-                let interrupt = self.regs.p.interrupt;
-                //self.regs.pc += 1;
-                //let lower = (self.regs.pc >> 8) as u8;
-                //self.push(lower);
-                if !interrupt {
-                    self.regs.p.break_mode = true;
-                    self.regs.pc += 1;
-                    self.push(((self.regs.pc & 0xFF00) >> 4) as u8);
-                    self.push((self.regs.pc & 0xFF) as u8);
-                    self.push_status();
-                    self.regs.p.interrupt = true;
-                    self.regs.pc = self.read(0xFFFE, ReadSize::Word);
-                } else {
-                    return;
-                }
+                // BRK is not maskable and skips a padding byte
+                self.regs.pc = self.regs.pc.wrapping_add(1);
+                self.push(((self.regs.pc & 0xFF00) >> 8) as u8);
+                self.push((self.regs.pc & 0xFF) as u8);
+                self.push_status(true);
+                self.regs.p.interrupt = true;
+                self.regs.pc = self.read(0xFFFE, ReadSize::Word);
             }
             Instruction::RTI => {
                 self.pop_status();
@@ -576,50 +670,27 @@ impl Cpu {
                 //print!("RTI");
             }
             Instruction::CMP => {
-                let m = match addressing {
-                    Addressing::Immediate => operand as u8,
-                    _ => self.read(operand, ReadSize::Byte) as u8,
-                };
-                self.regs.p.carry = self.regs.a >= m;
-                self.regs.p.zero = self.regs.a == m;
-                self.regs.p.negative = (self.regs.a as i8) > (m as i8);
+                let m = self.read_data(addressing, operand);
+                self.compare(self.regs.a, m);
                 //print!("CMP");
             }
             Instruction::CPX => {
-                let m = match addressing {
-                    Addressing::Immediate => operand as u8,
-                    _ => self.read(operand, ReadSize::Byte) as u8,
-                };
-                self.regs.p.carry = self.regs.x >= m;
-                self.regs.p.zero = self.regs.x == m;
-                self.regs.p.negative = self.regs.x > m;
+                let m = self.read_data(addressing, operand);
+                self.compare(self.regs.x, m);
                 //print!("CPX");
             }
             Instruction::CPY => {
-                let m = match addressing {
-                    Addressing::Immediate => operand as u8,
-                    _ => self.read(operand, ReadSize::Byte) as u8,
-                };
-                self.regs.p.carry = self.regs.y >= m;
-                self.regs.p.zero = self.regs.y == m;
-                self.regs.p.negative = self.regs.y > m;
+                let m = self.read_data(addressing, operand);
+                self.compare(self.regs.y, m);
                 //print!("CPY");
             }
             Instruction::INC => {
-                let data = self.read(operand, ReadSize::Byte);
-                let result = (data as i16 + 1) as u8;
-                self.bus.write_by_cpu(operand, result);
-                self.regs.p.zero = result == 0;
-                self.regs.p.negative = self.check_negative(&self.regs.x);
+                self.modify(addressing, operand, Self::increment);
                 //print!("INC");
             }
             Instruction::DEC => {
                 //print!("DEC");
-                let data = self.read(operand, ReadSize::Byte) as u8;
-                let result = (data as i16 - 1) as u8;
-                self.bus.write_by_cpu(operand, result);
-                self.regs.p.zero = result == 0;
-                self.regs.p.negative = self.check_negative(&result);
+                self.modify(addressing, operand, Self::decrement);
             }
             Instruction::INX => {
                 //print!("INX null\n : x:{:x}+1 ->", self.regs.x);
@@ -726,15 +797,15 @@ impl Cpu {
                 //print!(" : {} -> Y", self.regs.y);
             }
             Instruction::STA => {
-                self.bus.write_by_cpu(operand, self.regs.a as u8);
+                self.bus.write_by_cpu(operand, self.regs.a);
                 //print!("STA ${:x}\n a:{:x} -> {:x}", operand, self.regs.a, operand);
             }
             Instruction::STX => {
-                self.bus.write_by_cpu(operand, self.regs.x as u8);
+                self.bus.write_by_cpu(operand, self.regs.x);
                 //print!("STX x:{:x} -> {:x}", self.regs.x, operand);
             }
             Instruction::STY => {
-                self.bus.write_by_cpu(operand, self.regs.y as u8);
+                self.bus.write_by_cpu(operand, self.regs.y);
                 //print!("STY y:{:x} -> {:x}", self.regs.y, operand);
             }
             Instruction::TAX => {
@@ -787,7 +858,7 @@ impl Cpu {
                 //print!("PLA stack:{:x} -> A:{:x}", self.regs.sp, self.regs.a);
             }
             Instruction::PHP => {
-                self.push_status();
+                self.push_status(true);
                 //print!("PHP");
             }
             Instruction::PLP => {
@@ -796,138 +867,315 @@ impl Cpu {
             }
             Instruction::NOP => {
                 //print!("NOP");
+                match addressing {
+                    Addressing::Implied | Addressing::Immediate => {}
+                    _ => {
+                        // the multi-byte NOPs still perform a dummy read
+                        self.read(operand, ReadSize::Byte);
+                    }
+                }
+            }
+            Instruction::LAX => {
+                let data = self.read(operand, ReadSize::Byte) as u8;
+                self.regs.a = data;
+                self.regs.x = data;
+                self.set_zero_negative(data);
+            }
+            Instruction::SAX => {
+                self.bus.write_by_cpu(operand, self.regs.a & self.regs.x);
+            }
+            Instruction::DCP => {
+                let result = self.modify(addressing, operand, Self::decrement);
+                self.compare(self.regs.a, result);
+            }
+            Instruction::ISC => {
+                let result = self.modify(addressing, operand, Self::increment);
+                self.add_with_carry(!result);
+            }
+            Instruction::SLO => {
+                let result = self.modify(addressing, operand, Self::shift_left);
+                self.regs.a |= result;
+                self.set_zero_negative(self.regs.a);
+            }
+            Instruction::RLA => {
+                let result = self.modify(addressing, operand, Self::rotate_left);
+                self.regs.a &= result;
+                self.set_zero_negative(self.regs.a);
+            }
+            Instruction::SRE => {
+                let result = self.modify(addressing, operand, Self::shift_right);
+                self.regs.a ^= result;
+                self.set_zero_negative(self.regs.a);
+            }
+            Instruction::RRA => {
+                // ROR leaves its carry out for ADC
+                let result = self.modify(addressing, operand, Self::rotate_right);
+                self.add_with_carry(result);
+            }
+            Instruction::ANC => {
+                self.regs.a &= operand as u8;
+                self.set_zero_negative(self.regs.a);
+                self.regs.p.carry = self.regs.p.negative;
+            }
+            Instruction::ALR => {
+                let data = self.regs.a & operand as u8;
+                self.regs.a = self.shift_right(data);
+            }
+            Instruction::ARR => {
+                let data = self.regs.a & operand as u8;
+                self.regs.a = (data >> 1) | ((self.regs.p.carry as u8) << 7);
+                self.set_zero_negative(self.regs.a);
+                self.regs.p.carry = (self.regs.a & (1 << 6)) != 0;
+                self.regs.p.overflow = (((self.regs.a >> 6) ^ (self.regs.a >> 5)) & 1) != 0;
+            }
+            Instruction::AXS => {
+                let data = operand as u8;
+                let reg = self.regs.a & self.regs.x;
+                self.compare(reg, data);
+                self.regs.x = reg.wrapping_sub(data);
+            }
+            Instruction::LAS => {
+                let data = self.read(operand, ReadSize::Byte) as u8 & (self.regs.sp as u8);
+                self.regs.a = data;
+                self.regs.x = data;
+                self.regs.sp = (data as u16) | 0x0100;
+                self.set_zero_negative(data);
+            }
+            Instruction::ANE => {
+                self.regs.a = (self.regs.a | UNSTABLE_MAGIC) & self.regs.x & operand as u8;
+                self.set_zero_negative(self.regs.a);
+            }
+            Instruction::LXA => {
+                let data = (self.regs.a | UNSTABLE_MAGIC) & operand as u8;
+                self.regs.a = data;
+                self.regs.x = data;
+                self.set_zero_negative(data);
+            }
+            Instruction::SHA => {
+                self.store_and_high(operand, self.regs.y, self.regs.a & self.regs.x);
+            }
+            Instruction::SHX => {
+                self.store_and_high(operand, self.regs.y, self.regs.x);
+            }
+            Instruction::SHY => {
+                self.store_and_high(operand, self.regs.x, self.regs.y);
+            }
+            Instruction::TAS => {
+                self.regs.sp = ((self.regs.a & self.regs.x) as u16) | 0x0100;
+                self.store_and_high(operand, self.regs.y, self.regs.sp as u8);
+            }
+            Instruction::JAM => {
+                // the CPU gets stuck fetching the same opcode forever
+                self.regs.pc = self.regs.pc.wrapping_sub(1);
             }
         }
     }
 
-    pub fn run(&mut self) {
-        let nmi_int = *cpu_bus::NMI_INT.borrow();
-        if nmi_int {
-            println!("==NMI_INT==");
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.regs.a);
+        w.write_u8(self.regs.x);
+        w.write_u8(self.regs.y);
+        w.write_u16(self.regs.sp);
+        w.write_u16(self.regs.pc);
+        w.write_u8(u8::from(self.regs.p.clone()));
+        w.write_u64(self.cycles);
+        self.bus.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.regs.a = r.read_u8()?;
+        self.regs.x = r.read_u8()?;
+        self.regs.y = r.read_u8()?;
+        // the upper byte of the stack pointer is fixed
+        self.regs.sp = (r.read_u16()? & 0x00FF) | 0x0100;
+        self.regs.pc = r.read_u16()?;
+        self.regs.p = Status::from(r.read_u8()?);
+        self.cycles = r.read_u64()?;
+        self.bus.load_state(r)
+    }
+
+    // executes one instruction and returns the number of cycles it took
+    pub fn run(&mut self) -> usize {
+        let start_cycles = self.cycles;
+        if self.bus.poll_nmi() {
             self.nmi_handler();
-            *cpu_bus::NMI_INT.borrow_mut() = false;
+        } else if self.bus.irq() && !self.regs.p.interrupt {
+            // IRQ is level triggered: it fires until the device is acknowledged
+            self.irq_handler();
+        }
+        if let Some(mut tracer) = self.tracer.take() {
+            tracer(&self.trace());
+            self.tracer = Some(tracer);
         }
-        let pc_for_log = self.regs.pc;
         let opcode = self.fetch();
         let op_info = self.get_instruction_info(opcode);
-        let operand = self.fetch_operand(&op_info.1);
+        let (operand, page_crossed) = self.fetch_operand(&op_info.1);
+        self.cycles += op_info.2 as u64;
+        if page_crossed && op_info.0.has_page_cross_penalty() {
+            self.cycles += 1;
+        }
+        // the operand is read or written on the last cycle
+        self.bus.set_access_cycle((self.cycles - start_cycles - 1) as usize);
         self.exec(&op_info.0, &op_info.1, operand);
-        self.print_log(pc_for_log, opcode, operand, &op_info.0, &op_info.1);
+        self.cycles += self.bus.run_dma(self.cycles);
+
+        (self.cycles - start_cycles) as usize
     }
 
-    fn print_log(
-        &mut self,
-        pc: u16,
-        opcode: u16,
-        operand: u16,
-        inst: &Instruction,
-        addr: &Addressing,
-    ) {
-        print!("0x{:x}: {:>02x} ", pc, opcode);
-        match addr {
-            Addressing::Accumlator => {
-                print!("      | {:?}", inst);
-            }
-            Addressing::Immediate => {
-                print!(
-                    "{:>02x}    | {:?} #${:?}    ",
-                    &self.read(pc + 1, ReadSize::Byte),
-                    inst,
-                    operand
-                );
-            }
-            Addressing::Absolute => {
-                print!(
-                    "{:>02x} {:>02x} | {:?} ${:x}",
-                    &self.read(pc + 1, ReadSize::Byte),
-                    &self.read(pc + 2, ReadSize::Byte),
-                    inst,
-                    operand
-                );
-            }
-            Addressing::AbsoluteX => {
-                print!(
-                    "{:>02x} {:>02x} | {:?} ${:x}, X",
-                    &self.read(pc + 1, ReadSize::Byte),
-                    &self.read(pc + 2, ReadSize::Byte),
-                    inst,
-                    operand
-                );
-            }
-            Addressing::AbsoluteY => {
-                print!(
-                    "{:>02x} {:>02x} | {:?} ${:x}, Y  ",
-                    &self.read(pc + 1, ReadSize::Byte),
-                    &self.read(pc + 2, ReadSize::Byte),
-                    inst,
-                    operand
-                );
-            }
-            Addressing::ZeroPage => {
-                print!(
-                    "{:>02x}    | {:?} ${:x}",
-                    &self.read(pc + 1, ReadSize::Byte),
-                    inst,
-                    operand
-                );
-            }
-            Addressing::ZeroPageX => {
-                print!(
-                    "{:>02x}    | {:?} ${:x}, X",
-                    &self.read(pc + 1, ReadSize::Byte),
-                    inst,
-                    operand
-                );
-            }
-            Addressing::ZeroPageY => {
-                print!(
-                    "{:>02x}    | {:?} ${:x}, X",
-                    &self.read(pc + 1, ReadSize::Byte),
-                    inst,
-                    operand
-                );
-            }
-            Addressing::Implied => {
-                print!("      | {:?}", inst);
-            }
-            Addressing::Relative => {
-                print!(
-                    "{:>02x}    | {:?} ${:2x}    ",
-                    &self.read(pc + 1, ReadSize::Byte),
-                    inst,
-                    &self.read(pc + 1, ReadSize::Byte)
-                );
-            }
+    fn is_unofficial(opcode: u16, inst: &Instruction) -> bool {
+        match inst {
+            Instruction::NOP => opcode != 0xEA,
+            Instruction::SBC => opcode == 0xEB,
+            Instruction::LAX
+            | Instruction::SAX
+            | Instruction::DCP
+            | Instruction::ISC
+            | Instruction::SLO
+            | Instruction::RLA
+            | Instruction::SRE
+            | Instruction::RRA
+            | Instruction::ANC
+            | Instruction::ALR
+            | Instruction::ARR
+            | Instruction::AXS
+            | Instruction::LAS
+            | Instruction::ANE
+            | Instruction::LXA
+            | Instruction::SHA
+            | Instruction::SHX
+            | Instruction::SHY
+            | Instruction::TAS
+            | Instruction::JAM => true,
+            _ => false,
+        }
+    }
+
+    fn peek_word_zp(&self, addr: u8) -> u16 {
+        let lower = self.bus.peek(addr as u16) as u16;
+        let upper = self.bus.peek(addr.wrapping_add(1) as u16) as u16;
+        (upper << 8) | lower
+    }
+
+    /*
+     * Format the instruction at PC and the current registers
+     * in the same way as nestest.log (Nintendulator's trace format)
+     */
+    pub fn trace(&self) -> String {
+        let pc = self.regs.pc;
+        let opcode = self.bus.peek(pc) as u16;
+        let (inst, addressing, _) = self.get_instruction_info(opcode);
+        let arg1 = self.bus.peek(pc.wrapping_add(1));
+        let arg2 = self.bus.peek(pc.wrapping_add(2));
+        let arg16 = ((arg2 as u16) << 8) | arg1 as u16;
+
+        let bytes = match addressing {
+            Addressing::Implied | Addressing::Accumlator => format!("{:02X}", opcode),
+            Addressing::Immediate
+            | Addressing::ZeroPage
+            | Addressing::ZeroPageX
+            | Addressing::ZeroPageY
+            | Addressing::Relative
+            | Addressing::IndirectX
+            | Addressing::IndirectY => format!("{:02X} {:02X}", opcode, arg1),
+            Addressing::Absolute
+            | Addressing::AbsoluteX
+            | Addressing::AbsoluteY
+            | Addressing::Indirect => format!("{:02X} {:02X} {:02X}", opcode, arg1, arg2),
+        };
+
+        let operand = match addressing {
+            Addressing::Implied => String::new(),
+            Addressing::Accumlator => "A".to_string(),
+            Addressing::Immediate => format!("#${:02X}", arg1),
+            Addressing::ZeroPage => format!("${:02X} = {:02X}", arg1, self.bus.peek(arg1 as u16)),
+            Addressing::ZeroPageX | Addressing::ZeroPageY => {
+                let (name, index) = match addressing {
+                    Addressing::ZeroPageX => ("X", self.regs.x),
+                    _ => ("Y", self.regs.y),
+                };
+                let addr = arg1.wrapping_add(index);
+                format!(
+                    "${:02X},{} @ {:02X} = {:02X}",
+                    arg1,
+                    name,
+                    addr,
+                    self.bus.peek(addr as u16)
+                )
+            }
+            Addressing::Absolute => match inst {
+                Instruction::JMP | Instruction::JSR => format!("${:04X}", arg16),
+                _ => format!("${:04X} = {:02X}", arg16, self.bus.peek(arg16)),
+            },
+            Addressing::AbsoluteX | Addressing::AbsoluteY => {
+                let (name, index) = match addressing {
+                    Addressing::AbsoluteX => ("X", self.regs.x),
+                    _ => ("Y", self.regs.y),
+                };
+                let addr = arg16.wrapping_add(index as u16);
+                format!(
+                    "${:04X},{} @ {:04X} = {:02X}",
+                    arg16,
+                    name,
+                    addr,
+                    self.bus.peek(addr)
+                )
+            }
+            Addressing::Relative => format!(
+                "${:04X}",
+                pc.wrapping_add(2).wrapping_add(arg1 as i8 as u16)
+            ),
             Addressing::Indirect => {
-                print!(
-                    "{:>02x} {:>02x} | {:?} (${:x})",
-                    &self.read(pc + 1, ReadSize::Byte),
-                    &self.read(pc + 2, ReadSize::Byte),
-                    inst,
-                    operand
-                );
+                // the upper byte is fetched without carrying into the page
+                let upper_addr = (arg16 & 0xFF00) | (arg16.wrapping_add(1) & 0x00FF);
+                let addr = ((self.bus.peek(upper_addr) as u16) << 8) | self.bus.peek(arg16) as u16;
+                format!("(${:04X}) = {:04X}", arg16, addr)
             }
             Addressing::IndirectX => {
-                print!(
-                    "{:>02x}    | {:?} (${:2x}, X)",
-                    &self.read(pc + 1, ReadSize::Byte),
-                    inst,
-                    operand
-                );
+                let ptr = arg1.wrapping_add(self.regs.x);
+                let addr = self.peek_word_zp(ptr);
+                format!(
+                    "(${:02X},X) @ {:02X} = {:04X} = {:02X}",
+                    arg1,
+                    ptr,
+                    addr,
+                    self.bus.peek(addr)
+                )
             }
             Addressing::IndirectY => {
-                print!(
-                    "{:>02x}    | {:?} (${:2x}), Y ",
-                    &self.read(pc + 1, ReadSize::Byte),
-                    inst,
-                    operand
-                );
+                let base = self.peek_word_zp(arg1);
+                let addr = base.wrapping_add(self.regs.y as u16);
+                format!(
+                    "(${:02X}),Y = {:04X} @ {:04X} = {:02X}",
+                    arg1,
+                    base,
+                    addr,
+                    self.bus.peek(addr)
+                )
             }
-        }
-        println!(
-            "A: {:2x} X: {:2x} Y: {:2x} SP:{:4x}",
-            &self.regs.a, &self.regs.x, &self.regs.y, &self.regs.sp,
-        );
+        };
+
+        let mnemonic = match inst {
+            Instruction::ISC => "ISB".to_string(),
+            _ => format!("{:?}", inst),
+        };
+        let disasm = format!("{} {}", mnemonic, operand);
+
+        let ppu = self.bus.ppu();
+        format!(
+            "{:04X}  {:<8} {}{:<31} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+            pc,
+            bytes,
+            if Self::is_unofficial(opcode, &inst) { '*' } else { ' ' },
+            disasm.trim_end(),
+            self.regs.a,
+            self.regs.x,
+            self.regs.y,
+            u8::from(self.regs.p.clone()),
+            self.regs.sp as u8,
+            ppu.scanline(),
+            ppu.dot(),
+            self.cycles
+        )
     }
 
     fn get_instruction_info(&self, opcode: u16) -> (Instruction, Addressing, u8) {
@@ -1142,54 +1390,137 @@ impl Cpu {
             0xEA => (Instruction::NOP, Addressing::Implied, CYCLE[index]),
             /* Opecodes below are unofficial */
             // NOP
-            //0x1A => (Instruction::NOP, Addressing::Implied, CYCLE[index]),
-            //0x3A => (Instruction::NOP, Addressing::Implied, CYCLE[index]),
-            //0x5A => (Instruction::NOP, Addressing::Implied, CYCLE[index]),
-            //0x7A => (Instruction::NOP, Addressing::Implied, CYCLE[index]),
-            //0xDA => (Instruction::NOP, Addressing::Implied, CYCLE[index]),
-            //0xFA => (Instruction::NOP, Addressing::Implied, CYCLE[index]),
-            //0x02 => (Instruction::NOP, Addressing::Implied, CYCLE[index]),
-            //0x12 => (Instruction::NOP, Addressing::Implied, CYCLE[index]),
-            //0x22 => (Instruction::NOP, Addressing::Implied, CYCLE[index]),
-            //0x32 => (Instruction::NOP, Addressing::Implied, CYCLE[index]),
-            //0x42 => (Instruction::NOP, Addressing::Implied, CYCLE[index]),
-            //0x52 => (Instruction::NOP, Addressing::Implied, CYCLE[index]),
-            //0x62 => (Instruction::NOP, Addressing::Implied, CYCLE[index]),
-            //0x72 => (Instruction::NOP, Addressing::Implied, CYCLE[index]),
-            //0x92 => (Instruction::NOP, Addressing::Implied, CYCLE[index]),
-            //0xB2 => (Instruction::NOP, Addressing::Implied, CYCLE[index]),
-            //0xD2 => (Instruction::NOP, Addressing::Implied, CYCLE[index]),
-            //0xF2 => (Instruction::NOP, Addressing::Implied, CYCLE[index]),
-            //NOPD
-            //0x => (Instruction::NOPD, Addressing::Implied, CYCLE[index]),
-            //0x => (Instruction::NOPD, Addressing::Implied, CYCLE[index]),
-            //0x => (Instruction::NOPD, Addressing::Implied, CYCLE[index]),
-            //0x => (Instruction::NOPD, Addressing::Implied, CYCLE[index]),
-            //0x => (Instruction::NOPD, Addressing::Implied, CYCLE[index]),
-            //0x => (Instruction::NOPD, Addressing::Implied, CYCLE[index]),
-            //0x => (Instruction::NOPD, Addressing::Implied, CYCLE[index]),
-            //0x => (Instruction::NOPD, Addressing::Implied, CYCLE[index]),
-            //0x => (Instruction::NOPD, Addressing::Implied, CYCLE[index]),
-            //0x => (Instruction::NOPD, Addressing::Implied, CYCLE[index]),
-            //0x => (Instruction::NOPD, Addressing::Implied, CYCLE[index]),
-            //0x => (Instruction::NOPD, Addressing::Implied, CYCLE[index]),
-            //0x => (Instruction::NOPD, Addressing::Implied, CYCLE[index]),
-            //0x => (Instruction::NOPD, Addressing::Implied, CYCLE[index]),
-            ////NOPI
-            //0x => (Instruction::NOPI, Addressing::Implied, CYCLE[index]),
-            //0x => (Instruction::NOPI, Addressing::Implied, CYCLE[index]),
-            //0x => (Instruction::NOPI, Addressing::Implied, CYCLE[index]),
-            //0x => (Instruction::NOPI, Addressing::Implied, CYCLE[index]),
-            //0x => (Instruction::NOPI, Addressing::Implied, CYCLE[index]),
-            //0x => (Instruction::NOPI, Addressing::Implied, CYCLE[index]),
-            //0x => (Instruction::NOPI, Addressing::Implied, CYCLE[index]),
-
-            //0x => (Instruction::, Addressing::, CYCLE[index]),
-            //0x => (Instruction::, Addressing::, CYCLE[index]),
+            0x1A => (Instruction::NOP, Addressing::Implied, CYCLE[index]),
+            0x3A => (Instruction::NOP, Addressing::Implied, CYCLE[index]),
+            0x5A => (Instruction::NOP, Addressing::Implied, CYCLE[index]),
+            0x7A => (Instruction::NOP, Addressing::Implied, CYCLE[index]),
+            0xDA => (Instruction::NOP, Addressing::Implied, CYCLE[index]),
+            0xFA => (Instruction::NOP, Addressing::Implied, CYCLE[index]),
+            0x80 => (Instruction::NOP, Addressing::Immediate, CYCLE[index]),
+            0x82 => (Instruction::NOP, Addressing::Immediate, CYCLE[index]),
+            0x89 => (Instruction::NOP, Addressing::Immediate, CYCLE[index]),
+            0xC2 => (Instruction::NOP, Addressing::Immediate, CYCLE[index]),
+            0xE2 => (Instruction::NOP, Addressing::Immediate, CYCLE[index]),
+            0x04 => (Instruction::NOP, Addressing::ZeroPage, CYCLE[index]),
+            0x44 => (Instruction::NOP, Addressing::ZeroPage, CYCLE[index]),
+            0x64 => (Instruction::NOP, Addressing::ZeroPage, CYCLE[index]),
+            0x14 => (Instruction::NOP, Addressing::ZeroPageX, CYCLE[index]),
+            0x34 => (Instruction::NOP, Addressing::ZeroPageX, CYCLE[index]),
+            0x54 => (Instruction::NOP, Addressing::ZeroPageX, CYCLE[index]),
+            0x74 => (Instruction::NOP, Addressing::ZeroPageX, CYCLE[index]),
+            0xD4 => (Instruction::NOP, Addressing::ZeroPageX, CYCLE[index]),
+            0xF4 => (Instruction::NOP, Addressing::ZeroPageX, CYCLE[index]),
+            0x0C => (Instruction::NOP, Addressing::Absolute, CYCLE[index]),
+            0x1C => (Instruction::NOP, Addressing::AbsoluteX, CYCLE[index]),
+            0x3C => (Instruction::NOP, Addressing::AbsoluteX, CYCLE[index]),
+            0x5C => (Instruction::NOP, Addressing::AbsoluteX, CYCLE[index]),
+            0x7C => (Instruction::NOP, Addressing::AbsoluteX, CYCLE[index]),
+            0xDC => (Instruction::NOP, Addressing::AbsoluteX, CYCLE[index]),
+            0xFC => (Instruction::NOP, Addressing::AbsoluteX, CYCLE[index]),
+            //JAM
+            0x02 => (Instruction::JAM, Addressing::Implied, CYCLE[index]),
+            0x12 => (Instruction::JAM, Addressing::Implied, CYCLE[index]),
+            0x22 => (Instruction::JAM, Addressing::Implied, CYCLE[index]),
+            0x32 => (Instruction::JAM, Addressing::Implied, CYCLE[index]),
+            0x42 => (Instruction::JAM, Addressing::Implied, CYCLE[index]),
+            0x52 => (Instruction::JAM, Addressing::Implied, CYCLE[index]),
+            0x62 => (Instruction::JAM, Addressing::Implied, CYCLE[index]),
+            0x72 => (Instruction::JAM, Addressing::Implied, CYCLE[index]),
+            0x92 => (Instruction::JAM, Addressing::Implied, CYCLE[index]),
+            0xB2 => (Instruction::JAM, Addressing::Implied, CYCLE[index]),
+            0xD2 => (Instruction::JAM, Addressing::Implied, CYCLE[index]),
+            0xF2 => (Instruction::JAM, Addressing::Implied, CYCLE[index]),
+            //LAX
+            0xA7 => (Instruction::LAX, Addressing::ZeroPage, CYCLE[index]),
+            0xB7 => (Instruction::LAX, Addressing::ZeroPageY, CYCLE[index]),
+            0xAF => (Instruction::LAX, Addressing::Absolute, CYCLE[index]),
+            0xBF => (Instruction::LAX, Addressing::AbsoluteY, CYCLE[index]),
+            0xA3 => (Instruction::LAX, Addressing::IndirectX, CYCLE[index]),
+            0xB3 => (Instruction::LAX, Addressing::IndirectY, CYCLE[index]),
+            //SAX
+            0x87 => (Instruction::SAX, Addressing::ZeroPage, CYCLE[index]),
+            0x97 => (Instruction::SAX, Addressing::ZeroPageY, CYCLE[index]),
+            0x8F => (Instruction::SAX, Addressing::Absolute, CYCLE[index]),
+            0x83 => (Instruction::SAX, Addressing::IndirectX, CYCLE[index]),
+            //SBC
+            0xEB => (Instruction::SBC, Addressing::Immediate, CYCLE[index]),
+            //DCP
+            0xC7 => (Instruction::DCP, Addressing::ZeroPage, CYCLE[index]),
+            0xD7 => (Instruction::DCP, Addressing::ZeroPageX, CYCLE[index]),
+            0xCF => (Instruction::DCP, Addressing::Absolute, CYCLE[index]),
+            0xDF => (Instruction::DCP, Addressing::AbsoluteX, CYCLE[index]),
+            0xDB => (Instruction::DCP, Addressing::AbsoluteY, CYCLE[index]),
+            0xC3 => (Instruction::DCP, Addressing::IndirectX, CYCLE[index]),
+            0xD3 => (Instruction::DCP, Addressing::IndirectY, CYCLE[index]),
+            //ISC
+            0xE7 => (Instruction::ISC, Addressing::ZeroPage, CYCLE[index]),
+            0xF7 => (Instruction::ISC, Addressing::ZeroPageX, CYCLE[index]),
+            0xEF => (Instruction::ISC, Addressing::Absolute, CYCLE[index]),
+            0xFF => (Instruction::ISC, Addressing::AbsoluteX, CYCLE[index]),
+            0xFB => (Instruction::ISC, Addressing::AbsoluteY, CYCLE[index]),
+            0xE3 => (Instruction::ISC, Addressing::IndirectX, CYCLE[index]),
+            0xF3 => (Instruction::ISC, Addressing::IndirectY, CYCLE[index]),
+            //SLO
+            0x07 => (Instruction::SLO, Addressing::ZeroPage, CYCLE[index]),
+            0x17 => (Instruction::SLO, Addressing::ZeroPageX, CYCLE[index]),
+            0x0F => (Instruction::SLO, Addressing::Absolute, CYCLE[index]),
+            0x1F => (Instruction::SLO, Addressing::AbsoluteX, CYCLE[index]),
+            0x1B => (Instruction::SLO, Addressing::AbsoluteY, CYCLE[index]),
+            0x03 => (Instruction::SLO, Addressing::IndirectX, CYCLE[index]),
+            0x13 => (Instruction::SLO, Addressing::IndirectY, CYCLE[index]),
+            //RLA
+            0x27 => (Instruction::RLA, Addressing::ZeroPage, CYCLE[index]),
+            0x37 => (Instruction::RLA, Addressing::ZeroPageX, CYCLE[index]),
+            0x2F => (Instruction::RLA, Addressing::Absolute, CYCLE[index]),
+            0x3F => (Instruction::RLA, Addressing::AbsoluteX, CYCLE[index]),
+            0x3B => (Instruction::RLA, Addressing::AbsoluteY, CYCLE[index]),
+            0x23 => (Instruction::RLA, Addressing::IndirectX, CYCLE[index]),
+            0x33 => (Instruction::RLA, Addressing::IndirectY, CYCLE[index]),
+            //SRE
+            0x47 => (Instruction::SRE, Addressing::ZeroPage, CYCLE[index]),
+            0x57 => (Instruction::SRE, Addressing::ZeroPageX, CYCLE[index]),
+            0x4F => (Instruction::SRE, Addressing::Absolute, CYCLE[index]),
+            0x5F => (Instruction::SRE, Addressing::AbsoluteX, CYCLE[index]),
+            0x5B => (Instruction::SRE, Addressing::AbsoluteY, CYCLE[index]),
+            0x43 => (Instruction::SRE, Addressing::IndirectX, CYCLE[index]),
+            0x53 => (Instruction::SRE, Addressing::IndirectY, CYCLE[index]),
+            //RRA
+            0x67 => (Instruction::RRA, Addressing::ZeroPage, CYCLE[index]),
+            0x77 => (Instruction::RRA, Addressing::ZeroPageX, CYCLE[index]),
+            0x6F => (Instruction::RRA, Addressing::Absolute, CYCLE[index]),
+            0x7F => (Instruction::RRA, Addressing::AbsoluteX, CYCLE[index]),
+            0x7B => (Instruction::RRA, Addressing::AbsoluteY, CYCLE[index]),
+            0x63 => (Instruction::RRA, Addressing::IndirectX, CYCLE[index]),
+            0x73 => (Instruction::RRA, Addressing::IndirectY, CYCLE[index]),
+            //ANC
+            0x0B => (Instruction::ANC, Addressing::Immediate, CYCLE[index]),
+            0x2B => (Instruction::ANC, Addressing::Immediate, CYCLE[index]),
+            //ALR
+            0x4B => (Instruction::ALR, Addressing::Immediate, CYCLE[index]),
+            //ARR
+            0x6B => (Instruction::ARR, Addressing::Immediate, CYCLE[index]),
+            //AXS
+            0xCB => (Instruction::AXS, Addressing::Immediate, CYCLE[index]),
+            //LAS
+            0xBB => (Instruction::LAS, Addressing::AbsoluteY, CYCLE[index]),
+            //ANE
+            0x8B => (Instruction::ANE, Addressing::Immediate, CYCLE[index]),
+            //LXA
+            0xAB => (Instruction::LXA, Addressing::Immediate, CYCLE[index]),
+            //SHA
+            0x9F => (Instruction::SHA, Addressing::AbsoluteY, CYCLE[index]),
+            0x93 => (Instruction::SHA, Addressing::IndirectY, CYCLE[index]),
+            //SHX
+            0x9E => (Instruction::SHX, Addressing::AbsoluteY, CYCLE[index]),
+            //SHY
+            0x9C => (Instruction::SHY, Addressing::AbsoluteX, CYCLE[index]),
+            //TAS
+            0x9B => (Instruction::TAS, Addressing::AbsoluteY, CYCLE[index]),
             _ => panic!("{} unknown", opcode)
         }
     }
 
+    #[allow(dead_code)]
     fn print_stack(&mut self) {
         println!("<<<<<<<<<<<<<<<<<<<");
         let sp = 1 + self.regs.sp;
@@ -1211,7 +1542,7 @@ mod tests {
         let prom_buf = {
             let mut buf = [0; 0x8000];
             // copy the given program to the PROM
-            buf[..prog.len()].copy_from_slice(&prog);
+            buf[..prog.len()].copy_from_slice(prog);
 
             // set the reset vector to 0x8000 (the beginning of the PROM)
             buf[0xFFFC - 0x8000] = 0x00;
@@ -1220,12 +1551,16 @@ mod tests {
             buf
         };
 
-        let prom = rom::ProgramRom::new(&prom_buf);
-        let crom = rom::CharacterRom::new(&[]);
+        let rom = rom::Rom {
+            header: Default::default(),
+            prog_rom: rom::ProgramRom::new(&prom_buf),
+            chr_rom: rom::CharacterRom::new(&[]),
+        };
+        let cartridge = mapper::new(rom).unwrap();
         let wram = ram::Ram::new(0x0800);
-        let ppu = ppu::Ppu::new(crom);
+        let ppu = ppu::Ppu::new(cartridge.clone());
 
-        let cpu_bus = cpu_bus::CpuBus::new(wram, prom, ppu);
+        let cpu_bus = cpu_bus::CpuBus::new(wram, cartridge, ppu);
 
         Cpu::new(cpu_bus)
     }
@@ -1269,12 +1604,12 @@ mod tests {
         cpu.run();
         assert_eq!(cpu.regs.sp, 0x01FE); // sp should be incremented
         assert_eq!(cpu.regs.p, Status::from(status)); // status should not change after PHP
-        assert_eq!(cpu.bus.read_by_cpu(cpu.regs.sp+1), status); // status should be pushed onto the stack
+        assert_eq!(cpu.bus.read_by_cpu(cpu.regs.sp+1), status | 0b0011_0000); // status should be pushed onto the stack with B and bit 5 set
 
         cpu.regs.p = 0b1111_1111.into(); // alter status
         cpu.run();
         assert_eq!(cpu.regs.sp, 0x01FF); // sp should be decremented
-        assert_eq!(cpu.regs.p, Status::from(status)); // status should be the original state after PLP
+        assert_eq!(cpu.regs.p, Status::from((status | 0b0010_0000) & !0b0001_0000)); // status should be the original state after PLP (B is ignored, bit 5 is always set)
     }
 
     #[test]
@@ -1584,4 +1919,262 @@ mod tests {
         assert_eq!(cpu.regs.y, 0x4);
 
     }
+
+    #[test]
+    fn test_nmi() {
+        // LDA #$80; STA $2000 (enable NMI); loop: JMP loop
+        let mut cpu = configure_cpu(&[0xA9, 0x80, 0x8D, 0x00, 0x20, 0x4C, 0x05, 0x80]);
+        // NMI vector is $0000: INC $10; RTI
+        cpu.bus.write_by_cpu(0x0000, 0xE6);
+        cpu.bus.write_by_cpu(0x0001, 0x10);
+        cpu.bus.write_by_cpu(0x0002, 0x40);
+
+        let cycles = cpu.reset();
+        cpu.bus.tick(cycles);
+        while cpu.bus.ppu().frame() < 3 {
+            let cycles = cpu.run();
+            cpu.bus.tick(cycles);
+        }
+
+        // edge triggered: exactly one NMI per vblank
+        assert_eq!(cpu.bus.read_by_cpu(0x0010), 3);
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        let prog = [0xA9, 0x02,       //LDA #$02
+                    0x8D, 0x14, 0x40, //STA $4014
+                    0xA6, 0x00,       //LDX $00
+                    0x8D, 0x14, 0x40, //STA $4014
+        ];
+
+        let mut cpu = configure_cpu(&prog);
+        for i in 0..0x100 {
+            cpu.bus.write_by_cpu(0x0200 + i, i as u8);
+        }
+        // OAMADDR = $10: the copy starts there and wraps around
+        cpu.bus.write_by_cpu(0x2003, 0x10);
+
+        assert_eq!(cpu.reset(), 7);
+        assert_eq!(cpu.run(), 2); //LDA #$02
+        // the transfer starts on an odd cycle (7 + 2 + 4)
+        assert_eq!(cpu.run(), 4 + 514); //STA $4014
+        assert_eq!(cpu.run(), 3); //LDX $00
+        // ... and on an even cycle (7 + 2 + 518 + 3 + 4)
+        assert_eq!(cpu.run(), 4 + 513); //STA $4014
+
+        cpu.bus.write_by_cpu(0x2003, 0x00);
+        for i in 0..0x100u16 {
+            let expected = (i as u8).wrapping_sub(0x10);
+            assert_eq!(cpu.bus.read_by_cpu(0x2004), expected);
+        }
+    }
+
+    #[test]
+    fn test_oam_dma_from_ppu_registers() {
+        let prog = [0xA9, 0x20,       //LDA #$20
+                    0x8D, 0x14, 0x40, //STA $4014
+        ];
+
+        let mut cpu = configure_cpu(&prog);
+        cpu.bus.write_by_cpu(0x2003, 0x00);
+
+        cpu.reset();
+        cpu.run(); //LDA #$20
+        assert_eq!(cpu.run(), 4 + 514); //STA $4014
+
+        // write-only registers read back the PPU data bus
+        assert_eq!(cpu.bus.read_by_cpu(0x2000), 0x20);
+
+        // the registers are not read: OAM gets the open bus ($20 from the STA)
+        cpu.bus.write_by_cpu(0x2003, 0x00);
+        for _ in 0..0x100 {
+            assert_eq!(cpu.bus.read_by_cpu(0x2004), 0x20);
+        }
+    }
+
+    #[test]
+    fn test_save_state() {
+        let prog = [0xE8,             //INX
+                    0x86, 0x00,       //STX $00
+                    0xEE, 0x00, 0x03, //INC $0300
+                    0x4C, 0x00, 0x80, //JMP $8000
+        ];
+
+        let mut cpu = configure_cpu(&prog);
+        let run = |cpu: &mut Cpu, n: usize| {
+            for _ in 0..n {
+                let cycles = cpu.run();
+                cpu.bus.tick(cycles);
+            }
+        };
+        let save = |cpu: &Cpu| {
+            let mut w = StateWriter::new(0);
+            cpu.save_state(&mut w);
+            w.into_inner()
+        };
+
+        let cycles = cpu.reset();
+        cpu.bus.tick(cycles);
+        run(&mut cpu, 1000);
+        let saved = save(&cpu);
+
+        run(&mut cpu, 5000);
+        let expected = save(&cpu);
+
+        let mut r = StateReader::new(&saved, 0).unwrap();
+        assert_eq!(cpu.load_state(&mut r), Ok(()));
+        assert_eq!(r.finish(), Ok(()));
+        assert_eq!(save(&cpu), saved);
+
+        // the restored machine runs exactly as the original did
+        run(&mut cpu, 5000);
+        assert_eq!(save(&cpu), expected);
+    }
+
+    #[test]
+    fn test_joypad() {
+        let prog = [0xA9, 0x01,       //LDA #$01
+                    0x8D, 0x16, 0x40, //STA $4016
+                    0xA9, 0x00,       //LDA #$00
+                    0x8D, 0x16, 0x40, //STA $4016
+                    0xAD, 0x16, 0x40, //LDA $4016
+                    0xAE, 0x16, 0x40, //LDX $4016
+                    0xAC, 0x17, 0x40, //LDY $4017
+        ];
+
+        let mut cpu = configure_cpu(&prog);
+        cpu.bus
+            .joypad_mut(joypad::Player::One)
+            .set_button(joypad::Button::A, true);
+        cpu.bus
+            .joypad_mut(joypad::Player::Two)
+            .set_button(joypad::Button::A, true);
+        cpu.reset();
+        for _ in 0..7 {
+            cpu.run();
+        }
+
+        // the upper bits are open bus: the high byte of the operand
+        assert_eq!(cpu.regs.a, 0x41); // A
+        assert_eq!(cpu.regs.x, 0x40); // B
+        assert_eq!(cpu.regs.y, 0x41); // A of player 2
+    }
+
+    #[test]
+    fn test_cycles() {
+        let prog = [0xEA,             //NOP : 2 cycles
+                    0xBD, 0x00, 0x05, //LDA $0x500, X : 4 cycles
+                    0xBD, 0xFF, 0x05, //LDA $0x5FF, X : 4 + 1 cycles (page crossed)
+                    0x9D, 0xFF, 0x05, //STA $0x5FF, X : 5 cycles (no penalty)
+                    0xD0, 0x00,       //BNE +0 : 2 + 1 cycles (taken)
+                    0xF0, 0x00,       //BEQ +0 : 2 cycles (not taken)
+        ];
+
+        let mut cpu = configure_cpu(&prog);
+        assert_eq!(cpu.reset(), 7);
+        cpu.regs.x = 0x1;
+        cpu.regs.p.zero = false;
+
+        assert_eq!(cpu.run(), 2); //NOP
+        assert_eq!(cpu.run(), 4); //LDA $0x500, X
+        assert_eq!(cpu.run(), 5); //LDA $0x5FF, X
+        assert_eq!(cpu.run(), 5); //STA $0x5FF, X
+        cpu.regs.p.zero = false;
+        assert_eq!(cpu.run(), 3); //BNE
+        cpu.regs.p.zero = false;
+        assert_eq!(cpu.run(), 2); //BEQ
+        assert_eq!(cpu.cycles, 7 + 2 + 4 + 5 + 5 + 3 + 2);
+    }
+
+    #[test]
+    fn test_unofficial_inst() {
+        let prog = [0xA7, 0x10,       //LAX $10 : Zero Page
+                    0x87, 0x11,       //SAX $11 : Zero Page
+                    0xC7, 0x12,       //DCP $12 : Zero Page
+                    0xE7, 0x13,       //ISC $13 : Zero Page
+                    0x07, 0x14,       //SLO $14 : Zero Page
+                    0x4B, 0x03,       //ALR #$3 : Immediate
+                    0xCB, 0x01,       //AXS #$1 : Immediate
+                    0x0C, 0x00, 0x05, //NOP $0500 : Absolute
+        ];
+
+        let mut cpu = configure_cpu(&prog);
+        cpu.reset();
+
+        cpu.bus.write_by_cpu(0x10, 0x8F);
+        cpu.bus.write_by_cpu(0x12, 0x90);
+        cpu.bus.write_by_cpu(0x13, 0x0F);
+        cpu.bus.write_by_cpu(0x14, 0x81);
+
+        cpu.run(); //LAX $10
+        assert_eq!(cpu.regs.a, 0x8F);
+        assert_eq!(cpu.regs.x, 0x8F);
+        assert!(cpu.regs.p.negative);
+
+        cpu.regs.x = 0xF0;
+        cpu.run(); //SAX $11
+        assert_eq!(cpu.bus.read_by_cpu(0x11), 0x80);
+
+        cpu.run(); //DCP $12 : $12 = 0x8F, compare with A = 0x8F
+        assert_eq!(cpu.bus.read_by_cpu(0x12), 0x8F);
+        assert!(cpu.regs.p.zero);
+        assert!(cpu.regs.p.carry);
+
+        cpu.run(); //ISC $13 : $13 = 0x10, A = 0x8F - 0x10
+        assert_eq!(cpu.bus.read_by_cpu(0x13), 0x10);
+        assert_eq!(cpu.regs.a, 0x7F);
+        assert!(cpu.regs.p.overflow);
+        assert!(cpu.regs.p.carry);
+
+        cpu.run(); //SLO $14 : $14 = 0x02, A = 0x7F | 0x02
+        assert_eq!(cpu.bus.read_by_cpu(0x14), 0x02);
+        assert_eq!(cpu.regs.a, 0x7F);
+        assert!(cpu.regs.p.carry);
+
+        cpu.run(); //ALR #$3 : A = (0x7F & 0x03) >> 1
+        assert_eq!(cpu.regs.a, 0x01);
+        assert!(cpu.regs.p.carry);
+
+        cpu.regs.x = 0x03;
+        cpu.run(); //AXS #$1 : X = (0x01 & 0x03) - 1
+        assert_eq!(cpu.regs.x, 0x00);
+        assert!(cpu.regs.p.zero);
+        assert!(cpu.regs.p.carry);
+
+        assert_eq!(cpu.run(), 4); //NOP $0500
+        assert_eq!(cpu.regs.pc, 0x8011);
+    }
+
+    /*
+     * Run nestest.nes in automation mode (from $C000) and compare
+     * the trace line by line with the golden log from Nintendulator.
+     */
+    #[test]
+    fn test_nestest() {
+        let buffer = std::fs::read("nestest/nestest.nes").unwrap();
+        let cartridge = mapper::new(rom::load(buffer).unwrap()).unwrap();
+        let wram = ram::Ram::new(0x0800);
+        let ppu = ppu::Ppu::new(cartridge.clone());
+        let mut cpu = Cpu::new(cpu_bus::CpuBus::new(wram, cartridge, ppu));
+
+        let cycles = cpu.reset();
+        cpu.bus.tick(cycles);
+        cpu.regs.pc = 0xC000;
+        cpu.regs.sp = 0x01FD;
+        cpu.regs.p = 0x24.into();
+
+        let log = std::fs::read_to_string("nestest/nestest.log").unwrap();
+        for (i, expected) in log.lines().enumerate() {
+            let actual = cpu.trace();
+            assert_eq!(actual, expected, "nestest.log diverges at line {}", i + 1);
+
+            let cycles = cpu.run();
+            cpu.bus.tick(cycles);
+        }
+
+        // nestest reports failures in $02 (official) and $03 (unofficial)
+        assert_eq!(cpu.bus.read_by_cpu(0x02), 0x00);
+        assert_eq!(cpu.bus.read_by_cpu(0x03), 0x00);
+    }
 }
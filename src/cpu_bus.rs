@@ -1,31 +1,270 @@
 use num_traits::FromPrimitive;
 
+use crate::apu::Apu;
+use crate::dma::Dma;
+use crate::joypad::{Joypad, Player};
+use crate::mapper;
 use crate::ppu;
 use crate::ram;
-use crate::rom;
+use crate::savestate::{StateError, StateReader, StateWriter};
 
-use std::cell::RefCell;
-
-pub const NMI_INT: RefCell<bool> = RefCell::new(false);
+/* cycles a DMC sample fetch halts the CPU for */
+const DMC_STALL_CYCLES: usize = 4;
 
 pub struct CpuBus {
     wram: ram::Ram,
-    prog_rom: rom::ProgramRom,
+    cartridge: mapper::Cartridge,
     ppu: ppu::Ppu,
+    apu: Apu,
+    dma: Dma,
+    joypad1: Joypad,
+    joypad2: Joypad,
+    /* CPU cycles the current instruction was halted for by DMC fetches */
+    dmc_stall: usize,
+    /* the last value driven on the data bus */
+    open_bus: u8,
+    /* NMI edge detector: the last level of the PPU's /NMI output */
+    nmi_line: bool,
+    /* an edge waiting to be serviced at the next instruction boundary */
+    nmi_pending: bool,
+    /* an edge in the last cycle of an instruction, seen one instruction later */
+    nmi_delayed: bool,
+    /* cycle of the current instruction its register access happens on */
+    access_cycle: usize,
+    /* cycles of the current instruction the devices have been run for */
+    ticked: usize,
 }
 
 impl CpuBus {
-    pub fn new(wram: ram::Ram, prog_rom: rom::ProgramRom, ppu: ppu::Ppu) -> CpuBus {
+    pub fn new(wram: ram::Ram, cartridge: mapper::Cartridge, ppu: ppu::Ppu) -> CpuBus {
         CpuBus {
             wram,
-            prog_rom,
+            cartridge,
             ppu,
+            apu: Apu::new(),
+            dma: Dma::new(),
+            joypad1: Joypad::new(),
+            joypad2: Joypad::new(),
+            dmc_stall: 0,
+            open_bus: 0,
+            nmi_line: false,
+            nmi_pending: false,
+            nmi_delayed: false,
+            access_cycle: 0,
+            ticked: 0,
+        }
+    }
+
+    pub fn ppu(&self) -> &ppu::Ppu {
+        &self.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut ppu::Ppu {
+        &mut self.ppu
+    }
+
+    pub fn apu(&self) -> &Apu {
+        &self.apu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    pub fn joypad_mut(&mut self, player: Player) -> &mut Joypad {
+        match player {
+            Player::One => &mut self.joypad1,
+            Player::Two => &mut self.joypad2,
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        self.wram.save_state(w);
+        self.cartridge.borrow().save_state(w);
+        self.ppu.save_state(w);
+        self.apu.save_state(w);
+        self.dma.save_state(w);
+        self.joypad1.save_state(w);
+        self.joypad2.save_state(w);
+        w.write_u8(self.open_bus);
+        w.write_bool(self.nmi_line);
+        w.write_bool(self.nmi_pending);
+        w.write_bool(self.nmi_delayed);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.wram.load_state(r)?;
+        self.cartridge.borrow_mut().load_state(r)?;
+        self.ppu.load_state(r)?;
+        self.apu.load_state(r)?;
+        self.dma.load_state(r)?;
+        self.joypad1.load_state(r)?;
+        self.joypad2.load_state(r)?;
+        self.open_bus = r.read_u8()?;
+        self.nmi_line = r.read_bool()?;
+        self.nmi_pending = r.read_bool()?;
+        self.nmi_delayed = r.read_bool()?;
+        Ok(())
+    }
+
+    /*
+     * Read without side effects (for debugging and tracing).
+     * I/O registers are not readable this way and return 0xFF.
+     */
+    pub fn peek(&self, addr: u16) -> u8 {
+        if addr < 0x2000 {
+            self.wram.read(addr & 0x07FF)
+        } else if addr < 0x4020 {
+            0xFF
+        } else {
+            self.cartridge.borrow().read_prg(addr)
         }
     }
 
+    /*
+     * Read a byte of an OAM DMA source page. The registers are left
+     * alone: a page in $2000-$401F reads as open bus.
+     */
+    fn dma_read(&self, addr: u16) -> u8 {
+        if addr < 0x2000 {
+            self.wram.read(addr & 0x07FF)
+        } else if addr < 0x4020 {
+            self.open_bus
+        } else {
+            self.cartridge.borrow().read_prg(addr)
+        }
+    }
+
+    /*
+     * Level of the shared IRQ line (true: asserted)
+     */
+    pub fn irq(&self) -> bool {
+        self.cartridge.borrow().irq() || self.apu.irq()
+    }
+
+    /*
+     * Take the latched NMI edge, if any
+     */
+    pub fn poll_nmi(&mut self) -> bool {
+        std::mem::replace(&mut self.nmi_pending, false)
+    }
+
+    /*
+     * Run a pending OAM DMA transfer and return the number of CPU cycles
+     * the CPU is stalled for
+     */
+    pub fn run_dma(&mut self, cpu_cycles: u64) -> u64 {
+        let page = match self.dma.take() {
+            Some(page) => page,
+            None => return 0,
+        };
+
+        let base = (page as u16) << 8;
+        for i in 0..0x100 {
+            let data = self.dma_read(base | i);
+            self.ppu.write(ppu::RegType::OAMDATA, data);
+        }
+
+        Dma::stall_cycles(cpu_cycles)
+    }
+
+    /*
+     * DMC sample fetch: the CPU is halted for 4 cycles while the APU
+     * takes over the bus. The rest of the machine goes on meanwhile.
+     */
+    fn dmc_fetch(&mut self, addr: u16) {
+        let data = self.read_by_cpu(addr);
+        self.apu.dmc_fill(data);
+
+        for _ in 0..DMC_STALL_CYCLES {
+            self.clock_devices(false);
+        }
+        self.dmc_stall += DMC_STALL_CYCLES;
+    }
+
+    /*
+     * Set the cycle of the instruction about to be executed on which it
+     * reads or writes its operand. The CPU only runs the devices once the
+     * instruction is done: register accesses use this to catch up.
+     */
+    pub fn set_access_cycle(&mut self, cycle: usize) {
+        self.access_cycle = cycle;
+    }
+
+    /*
+     * Run the devices up to the access cycle of the current instruction
+     * so that a register access sees them as they are on that cycle
+     */
+    fn catch_up(&mut self) {
+        while self.ticked < self.access_cycle {
+            self.step_cycle(false);
+        }
+    }
+
+    /*
+     * Run the access cycle itself and return whether a DMC fetch halted
+     * the CPU on it
+     */
+    fn step_access_cycle(&mut self) -> bool {
+        let dmc_stall = self.dmc_stall;
+        if self.ticked == self.access_cycle {
+            /* the access is on the last cycle */
+            self.step_cycle(true);
+        }
+        self.dmc_stall != dmc_stall
+    }
+
+    /*
+     * Advance the devices on the bus to the end of the instruction just
+     * executed, which took the given number of CPU cycles. Returns the
+     * number of cycles DMC fetches halted the CPU for on top of them.
+     */
+    pub fn tick(&mut self, cpu_cycles: usize) -> usize {
+        if self.nmi_delayed {
+            self.nmi_delayed = false;
+            self.nmi_pending = true;
+        }
+
+        while self.ticked < cpu_cycles {
+            self.step_cycle(self.ticked + 1 == cpu_cycles);
+        }
+
+        self.ticked = 0;
+        std::mem::replace(&mut self.dmc_stall, 0)
+    }
+
+    fn step_cycle(&mut self, last: bool) {
+        self.clock_devices(last);
+        self.ticked += 1;
+        if let Some(addr) = self.apu.dmc_fetch_addr() {
+            self.dmc_fetch(addr);
+        }
+    }
+
+    fn clock_devices(&mut self, last: bool) {
+        for _ in 0..ppu::DOTS_PER_CPU_CYCLE {
+            self.ppu.step();
+        }
+        self.apu.step();
+
+        /*
+         * The CPU samples NMI before the last cycle of an instruction.
+         * An edge later than that waits for the next instruction.
+         */
+        let nmi_line = self.ppu.nmi_output();
+        if nmi_line && !self.nmi_line {
+            if last {
+                self.nmi_delayed = true;
+            } else {
+                self.nmi_pending = true;
+            }
+        }
+        self.nmi_line = nmi_line;
+    }
+
     pub fn read_by_cpu(&mut self, addr: u16) -> u8 {
         //println!("read_by_cpu {:x}", addr);
-        if addr < 0x0800 {
+        let data = if addr < 0x0800 {
             // WRAM
             self.wram.read(addr)
         } else if addr < 0x1000 {
@@ -36,45 +275,53 @@ impl CpuBus {
             self.wram.read(addr - 0x1000)
         } else if addr < 0x2000 {
             // WRAM Mirror
-            let data = self.wram.read(addr - 0x1800);
-            *NMI_INT.borrow_mut() = (((data) & (1 << 7)) >> 7) == 1;
-            println!("hogehoge {}", *NMI_INT.borrow());
-            data
+            self.wram.read(addr - 0x1800)
         } else if addr < 0x2008 {
             // PPU Register
+            self.catch_up();
             self.ppu
                 .read(ppu::RegType::from_u16(addr - 0x2000).unwrap())
         } else if addr < 0x4000 {
             // PPU Mirror
             0
         } else if addr == 0x4016 {
-            // Joypad P1
-            0
+            // Joypad P1 (the upper bits are open bus)
+            (self.open_bus & 0xE0) | self.read_joypad(Player::One)
         } else if addr == 0x4017 {
             // Joypad P2
+            (self.open_bus & 0xE0) | self.read_joypad(Player::Two)
+        } else if addr == 0x4015 {
+            // APU Status
+            self.apu.read_status()
+        } else if addr < 0x4020 {
+            // APU and I/O
             0
-        } else if addr < 0x6000 {
-            // Extended ROM
-            0
-        } else if addr < 0x8000 {
-            // Extended RAM
-            0
-        } else if addr < 0xC000 {
-            // PRG-ROM
-            self.prog_rom.read(addr - 0x8000)
         } else {
-            //0xC000 ~ 0xFFFF   // PRG-ROM
-            let base_addr = if self.prog_rom.data.len() == 0x4000 {
-                0xC000
-            } else {
-                0x8000
-            };
+            // Extended ROM, Extended RAM and PRG-ROM on the cartridge
+            self.cartridge.borrow().read_prg(addr)
+        };
+
+        self.open_bus = data;
+        data
+    }
 
-            self.prog_rom.read(addr - base_addr)
+    /*
+     * If a DMC fetch halts the CPU in the middle of a controller read,
+     * the read is repeated once the CPU resumes. The halted read clocks
+     * the shift register all the same, so a bit is lost. This is why
+     * games such as SMB3 read the controllers until two reads in a row
+     * agree.
+     */
+    fn read_joypad(&mut self, player: Player) -> u8 {
+        self.catch_up();
+        if self.step_access_cycle() {
+            self.joypad_mut(player).read();
         }
+        self.joypad_mut(player).read()
     }
 
     pub fn write_by_cpu(&mut self, addr: u16, data: u8) {
+        self.open_bus = data;
         if addr < 0x800 {
             self.wram.write(addr, data);
         } else if addr < 0x1000 {
@@ -87,13 +334,20 @@ impl CpuBus {
             // WRAM Mirror
             self.wram.write(addr - 0x1800, data)
         } else if addr < 0x2008 {
+            self.catch_up();
             self.ppu
                 .write(ppu::RegType::from_u16(addr - 0x2000).unwrap(), data)
-        } else if addr < 0x4020 && addr >= 0x4000 {
-            //0x4014 -> dma
-            //0x4016 -> joypad1
-            //0x4017 -> joypad2
-            //others -> apu
+        } else if addr == 0x4014 {
+            self.dma.write(data);
+        } else if addr == 0x4016 {
+            // the strobe is shared by both controllers
+            self.joypad1.write(data);
+            self.joypad2.write(data);
+        } else if (0x4000..0x4020).contains(&addr) {
+            // APU
+            self.apu.write(addr, data);
+        } else if addr >= 0x4020 {
+            self.cartridge.borrow_mut().write_prg(addr, data);
         }
     }
 }
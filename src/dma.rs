@@ -1,6 +1,48 @@
-struct Dma {}
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+/*
+ * OAM DMA ($4014)
+ *
+ * Writing $XX to $4014 copies the 256 bytes at $XX00-$XXFF into OAM
+ * through OAMDATA. The CPU is halted while the transfer runs.
+ */
+pub struct Dma {
+    page: Option<u8>,
+}
 
 impl Dma {
-    fn write() {}
-    fn read() {}
+    /* one dummy cycle, then 256 alternating read/write pairs */
+    const TRANSFER_CYCLES: u64 = 513;
+
+    pub fn new() -> Dma {
+        Dma { page: None }
+    }
+
+    pub fn write(&mut self, page: u8) {
+        self.page = Some(page);
+    }
+
+    /*
+     * Take the requested transfer, if any
+     */
+    pub fn take(&mut self) -> Option<u8> {
+        self.page.take()
+    }
+
+    /*
+     * Number of CPU cycles the transfer halts the CPU for.
+     * Starting on an odd cycle costs one extra alignment cycle.
+     */
+    pub fn stall_cycles(cpu_cycles: u64) -> u64 {
+        Self::TRANSFER_CYCLES + (cpu_cycles & 1)
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_option_u8(self.page);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.page = r.read_option_u8()?;
+        Ok(())
+    }
 }
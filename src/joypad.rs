@@ -0,0 +1,139 @@
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+/// Controller port ($4016 / $4017)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Player {
+    One,
+    Two,
+}
+
+/// Standard controller button, in the order they are read out
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Button {
+    A = 0,
+    B = 1,
+    Select = 2,
+    Start = 3,
+    Up = 4,
+    Down = 5,
+    Left = 6,
+    Right = 7,
+}
+
+/*
+ * Standard controller ($4016/$4017)
+ *
+ * While strobe (bit 0 of $4016) is high the shift register is
+ * continuously reloaded with the button state. Once it goes low each
+ * read returns the next button in the order A, B, Select, Start, Up,
+ * Down, Left, Right, then 1s.
+ */
+pub struct Joypad {
+    buttons: u8, // pressed state, one bit per Button
+    shift: u8,
+    strobe: bool,
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            buttons: 0,
+            shift: 0,
+            strobe: false,
+        }
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        let mask = 1 << button as u8;
+        if pressed {
+            self.buttons |= mask;
+        } else {
+            self.buttons &= !mask;
+        }
+
+        if self.strobe {
+            self.shift = self.buttons;
+        }
+    }
+
+    /*
+     * $4016 write: bit 0 is the strobe shared by both controllers
+     */
+    pub fn write(&mut self, data: u8) {
+        self.strobe = (data & 1) != 0;
+        if self.strobe {
+            self.shift = self.buttons;
+        }
+    }
+
+    /*
+     * Serial read: the button bit in bit 0 (the other bits are open bus)
+     */
+    pub fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.buttons & 1;
+        }
+
+        let bit = self.shift & 1;
+        /* an official controller shifts in 1s after the 8th read */
+        self.shift = (self.shift >> 1) | 0x80;
+        bit
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.buttons);
+        w.write_u8(self.shift);
+        w.write_bool(self.strobe);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.buttons = r.read_u8()?;
+        self.shift = r.read_u8()?;
+        self.strobe = r.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(joypad: &mut Joypad) -> Vec<u8> {
+        (0..10).map(|_| joypad.read()).collect()
+    }
+
+    #[test]
+    fn test_serial_read() {
+        let mut joypad = Joypad::new();
+        joypad.set_button(Button::A, true);
+        joypad.set_button(Button::Start, true);
+        joypad.set_button(Button::Left, true);
+
+        joypad.write(1);
+        joypad.write(0);
+        assert_eq!(read_all(&mut joypad), [1, 0, 0, 1, 0, 0, 1, 0, 1, 1]);
+
+        /* the latched state does not change until the next strobe */
+        joypad.set_button(Button::A, false);
+        joypad.write(1);
+        joypad.write(0);
+        assert_eq!(read_all(&mut joypad), [0, 0, 0, 1, 0, 0, 1, 0, 1, 1]);
+    }
+
+    #[test]
+    fn test_strobe_high() {
+        let mut joypad = Joypad::new();
+        joypad.write(1);
+
+        /* strobe high: always the current state of A */
+        assert_eq!(joypad.read(), 0);
+        joypad.set_button(Button::A, true);
+        assert_eq!(joypad.read(), 1);
+        assert_eq!(joypad.read(), 1);
+
+        joypad.write(0);
+        joypad.set_button(Button::B, true);
+        assert_eq!(joypad.read(), 1);
+        assert_eq!(joypad.read(), 0);
+    }
+}
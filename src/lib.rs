@@ -0,0 +1,48 @@
+//! A NES emulator core.
+//!
+//! [`Nes`] is the whole console. It does nothing on its own: the caller
+//! runs it a frame (or an instruction) at a time, presses the buttons,
+//! and collects the pictures through a [`VideoSink`] and the audio with
+//! [`Nes::read_samples`]. Nothing here needs a display or a sound card.
+//!
+//! ```no_run
+//! use nes::{Button, MemorySink, Nes, Player};
+//!
+//! let mut nes = Nes::load("game.nes")?;
+//! let video = MemorySink::new();
+//! nes.set_video_sink(Box::new(video.clone()));
+//!
+//! nes.set_button(Player::One, Button::Start, true);
+//! nes.run_frame();
+//! assert_eq!(video.frame_count(), 1);
+//!
+//! let mut audio = [0.0; 4096];
+//! let n = nes.read_samples(&mut audio);
+//! # let _ = n;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+mod apu;
+mod cpu;
+mod cpu_bus;
+mod dma;
+mod joypad;
+mod mapper;
+mod nes;
+mod ppu;
+mod ram;
+mod rewind;
+mod rom;
+mod savestate;
+mod video;
+mod wav;
+
+pub use crate::apu::{Channel, DEFAULT_SAMPLE_RATE};
+pub use crate::cpu::Tracer;
+pub use crate::joypad::{Button, Player};
+pub use crate::mapper::Mirroring;
+pub use crate::nes::{Nes, SCREEN_HEIGHT, SCREEN_WIDTH};
+pub use crate::rom::{ConsoleType, HeaderFormat, RomError, RomHeader, Timing};
+pub use crate::savestate::StateError;
+pub use crate::video::{MemorySink, NullSink, VideoSink};
+pub use crate::wav::WavSink;
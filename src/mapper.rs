@@ -0,0 +1,82 @@
+use crate::rom;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+
+mod axrom;
+mod mmc1;
+mod mmc3;
+mod nrom;
+
+pub use axrom::Axrom;
+pub use mmc1::Mmc1;
+pub use mmc3::Mmc3;
+pub use nrom::Nrom;
+
+/// How the nametables are laid out in the PPU address space
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    SingleScreenLower,
+    SingleScreenUpper,
+    FourScreen,
+}
+
+/*
+ * A cartridge board: PRG-ROM, CHR-ROM/RAM, PRG-RAM and
+ * whatever bank switching hardware sits between them and the buses
+ */
+pub trait Mapper {
+    /*
+     * CPU side: $4020-$FFFF
+     * (expansion area, PRG-RAM at $6000-$7FFF and PRG-ROM at $8000-$FFFF)
+     */
+    fn read_prg(&self, addr: u16) -> u8;
+    fn write_prg(&mut self, addr: u16, data: u8);
+
+    /*
+     * PPU side: pattern tables at $0000-$1FFF
+     */
+    fn read_chr(&self, addr: u16) -> u8;
+    fn write_chr(&mut self, addr: u16, data: u8);
+
+    fn mirroring(&self) -> Mirroring;
+
+    /*
+     * Every address the PPU puts on its bus, stamped with the PPU dot count.
+     * Boards watching address line A12 (MMC3) count scanlines with this.
+     */
+    fn ppu_bus_addr(&mut self, _addr: u16, _ppu_cycle: u64) {}
+
+    /* level of the cartridge IRQ line (true: asserted) */
+    fn irq(&self) -> bool {
+        false
+    }
+
+    /*
+     * Save states: the bank registers and the RAM on the board
+     */
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError>;
+}
+
+/* shared by the CPU bus and the PPU */
+pub type Cartridge = Rc<RefCell<dyn Mapper>>;
+
+/*
+ * Build the cartridge for the mapper number in the iNES header
+ */
+pub fn new(rom: rom::Rom) -> io::Result<Cartridge> {
+    let cartridge: Cartridge = match rom.header.mapper {
+        0 => Rc::new(RefCell::new(Nrom::new(rom))),
+        1 => Rc::new(RefCell::new(Mmc1::new(rom))),
+        4 => Rc::new(RefCell::new(Mmc3::new(rom))),
+        7 => Rc::new(RefCell::new(Axrom::new(rom))),
+        n => return Err(io::Error::other(format!("Unsupported mapper: {}", n))),
+    };
+
+    Ok(cartridge)
+}
@@ -0,0 +1,87 @@
+use crate::mapper::{Mapper, Mirroring};
+use crate::rom::{CharacterRom, Rom};
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x8000; // 32KiB
+
+/*
+ * Mapper 7: AxROM
+ * 32KiB PRG-ROM banks and one-screen mirroring, both selected by
+ * writing to $8000-$FFFF. CHR is always 8KiB of RAM.
+ */
+pub struct Axrom {
+    prog_rom: Vec<u8>,
+    chr_rom: CharacterRom,
+    chr_ram: Vec<u8>,
+    bank: u8,
+}
+
+impl Axrom {
+    pub fn new(rom: Rom) -> Axrom {
+        let chr_len = rom.chr_rom.data.len();
+        Axrom {
+            prog_rom: rom.prog_rom.data,
+            chr_rom: rom.chr_rom,
+            chr_ram: if chr_len == 0 {
+                vec![0; 0x2000]
+            } else {
+                Vec::new()
+            },
+            bank: 0,
+        }
+    }
+}
+
+impl Mapper for Axrom {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr {
+            0x8000..=0xFFFF => {
+                let nbanks = (self.prog_rom.len() / PRG_BANK_SIZE).max(1);
+                let bank = (self.bank & 0b0000_0111) as usize % nbanks;
+                let offset = bank * PRG_BANK_SIZE + (addr as usize - 0x8000);
+                self.prog_rom[offset % self.prog_rom.len()]
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        if let 0x8000..=0xFFFF = addr {
+            self.bank = data;
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        if self.chr_rom.data.is_empty() {
+            self.chr_ram[addr as usize]
+        } else {
+            self.chr_rom.data[addr as usize]
+        }
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        /* CHR-ROM is not writable */
+        if self.chr_rom.data.is_empty() {
+            self.chr_ram[addr as usize] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if (self.bank & 0b0001_0000) == 0 {
+            Mirroring::SingleScreenLower
+        } else {
+            Mirroring::SingleScreenUpper
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.chr_ram);
+        w.write_u8(self.bank);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_bytes_into(&mut self.chr_ram)?;
+        self.bank = r.read_u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,304 @@
+use crate::mapper::{Mapper, Mirroring};
+use crate::ram::Ram;
+use crate::rom::{CharacterRom, ProgramRom, Rom};
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x4000; // 16KiB
+const CHR_BANK_SIZE: usize = 0x1000; // 4KiB
+
+/*
+ * Mapper 1: Nintendo MMC1 (SxROM)
+ *
+ * Registers are written one bit at a time through a 5-bit shift register
+ * at $8000-$FFFF. The fifth write copies the value into the register
+ * selected by bits 13 and 14 of the address.
+ */
+pub struct Mmc1 {
+    prog_rom: ProgramRom,
+    chr_rom: CharacterRom,
+    chr_ram: Vec<u8>,
+    prg_ram: Ram,
+    shift: u8,
+    shift_count: u8,
+    control: u8,   // $8000-$9FFF
+    chr_bank0: u8, // $A000-$BFFF
+    chr_bank1: u8, // $C000-$DFFF
+    prg_bank: u8,  // $E000-$FFFF
+}
+
+impl Mmc1 {
+    pub fn new(rom: Rom) -> Mmc1 {
+        let chr_len = rom.chr_rom.data.len();
+        Mmc1 {
+            prog_rom: rom.prog_rom,
+            chr_rom: rom.chr_rom,
+            chr_ram: if chr_len == 0 {
+                vec![0; 0x2000]
+            } else {
+                Vec::new()
+            },
+            prg_ram: Ram::new(0x2000),
+            shift: 0,
+            shift_count: 0,
+            /* the last PRG bank is fixed at $C000 on power up */
+            control: 0b0_1100,
+            chr_bank0: 0,
+            chr_bank1: 0,
+            prg_bank: 0,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x8000..=0x9FFF => self.control = data,
+            0xA000..=0xBFFF => self.chr_bank0 = data,
+            0xC000..=0xDFFF => self.chr_bank1 = data,
+            _ => self.prg_bank = data,
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        (self.prg_bank & 0b1_0000) == 0
+    }
+
+    /*
+     * 512KiB boards (SUROM) use bit 4 of the CHR bank register
+     * to select the 256KiB half of PRG-ROM
+     */
+    fn prg_outer_bank(&self) -> usize {
+        if self.prog_rom.data.len() > 0x40000 {
+            (self.chr_bank0 as usize & 0b1_0000) >> 4
+        } else {
+            0
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let nbanks = (self.prog_rom.data.len() / PRG_BANK_SIZE).min(16);
+        let bank = (self.prg_bank & 0b0_1111) as usize;
+        let slot = (addr as usize - 0x8000) / PRG_BANK_SIZE;
+
+        let bank = match (self.control & 0b0_1100) >> 2 {
+            /* switch 32KiB at $8000, ignoring the low bit of the bank number */
+            0 | 1 => (bank & !1) + slot,
+            /* fix the first bank at $8000 and switch $C000 */
+            2 => {
+                if slot == 0 {
+                    0
+                } else {
+                    bank
+                }
+            }
+            /* fix the last bank at $C000 and switch $8000 */
+            _ => {
+                if slot == 0 {
+                    bank
+                } else {
+                    nbanks - 1
+                }
+            }
+        };
+
+        let bank = (bank % nbanks) + self.prg_outer_bank() * 16;
+        bank * PRG_BANK_SIZE + (addr as usize % PRG_BANK_SIZE)
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let slot = addr as usize / CHR_BANK_SIZE;
+        let bank = if (self.control & 0b1_0000) == 0 {
+            /* switch 8KiB at a time, ignoring the low bit of the bank number */
+            (self.chr_bank0 as usize & !1) + slot
+        } else if slot == 0 {
+            self.chr_bank0 as usize
+        } else {
+            self.chr_bank1 as usize
+        };
+
+        let len = if self.chr_rom.data.is_empty() {
+            self.chr_ram.len()
+        } else {
+            self.chr_rom.data.len()
+        };
+        (bank * CHR_BANK_SIZE + (addr as usize % CHR_BANK_SIZE)) % len
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => self.prg_ram.read(addr - 0x6000),
+            0x8000..=0xFFFF => self.prog_rom.data[self.prg_offset(addr)],
+            _ => 0,
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                self.prg_ram.write(addr - 0x6000, data);
+            }
+            0x8000..=0xFFFF => {
+                if (data & 0b1000_0000) != 0 {
+                    /* reset the shift register and fix the last PRG bank */
+                    self.shift = 0;
+                    self.shift_count = 0;
+                    self.control |= 0b0_1100;
+                    return;
+                }
+
+                self.shift |= (data & 1) << self.shift_count;
+                self.shift_count += 1;
+                if self.shift_count == 5 {
+                    let value = self.shift;
+                    self.write_register(addr, value);
+                    self.shift = 0;
+                    self.shift_count = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        let offset = self.chr_offset(addr);
+        if self.chr_rom.data.is_empty() {
+            self.chr_ram[offset]
+        } else {
+            self.chr_rom.data[offset]
+        }
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        /* CHR-ROM is not writable */
+        if self.chr_rom.data.is_empty() {
+            let offset = self.chr_offset(addr);
+            self.chr_ram[offset] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0b0_0011 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        self.prg_ram.save_state(w);
+        w.write_bytes(&self.chr_ram);
+        w.write_u8(self.shift);
+        w.write_u8(self.shift_count);
+        w.write_u8(self.control);
+        w.write_u8(self.chr_bank0);
+        w.write_u8(self.chr_bank1);
+        w.write_u8(self.prg_bank);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.prg_ram.load_state(r)?;
+        r.read_bytes_into(&mut self.chr_ram)?;
+        self.shift = r.read_u8()?;
+        self.shift_count = r.read_u8()?;
+        self.control = r.read_u8()?;
+        self.chr_bank0 = r.read_u8()?;
+        self.chr_bank1 = r.read_u8()?;
+        self.prg_bank = r.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::RomHeader;
+
+    fn configure_mmc1() -> Mmc1 {
+        /* 8 PRG banks and 4 CHR banks, each filled with its bank number */
+        let prg: Vec<u8> = (0..8 * PRG_BANK_SIZE)
+            .map(|i| (i / PRG_BANK_SIZE) as u8)
+            .collect();
+        let chr: Vec<u8> = (0..4 * CHR_BANK_SIZE)
+            .map(|i| (i / CHR_BANK_SIZE) as u8)
+            .collect();
+
+        Mmc1::new(Rom {
+            header: RomHeader {
+                mapper: 1,
+                ..Default::default()
+            },
+            prog_rom: ProgramRom::new(&prg),
+            chr_rom: CharacterRom::new(&chr),
+        })
+    }
+
+    fn write_serial(mmc1: &mut Mmc1, addr: u16, value: u8) {
+        for i in 0..5 {
+            mmc1.write_prg(addr, (value >> i) & 1);
+        }
+    }
+
+    #[test]
+    fn test_prg_banking() {
+        let mut mmc1 = configure_mmc1();
+
+        /* power on: the last bank is fixed at $C000 */
+        assert_eq!(mmc1.read_prg(0x8000), 0);
+        assert_eq!(mmc1.read_prg(0xC000), 7);
+
+        write_serial(&mut mmc1, 0xE000, 3);
+        assert_eq!(mmc1.read_prg(0x8000), 3);
+        assert_eq!(mmc1.read_prg(0xFFFF), 7);
+
+        /* fix the first bank at $8000 */
+        write_serial(&mut mmc1, 0x8000, 0b0_1000);
+        assert_eq!(mmc1.read_prg(0x8000), 0);
+        assert_eq!(mmc1.read_prg(0xC000), 3);
+
+        /* 32KiB mode ignores the low bit */
+        write_serial(&mut mmc1, 0x8000, 0b0_0000);
+        assert_eq!(mmc1.read_prg(0x8000), 2);
+        assert_eq!(mmc1.read_prg(0xC000), 3);
+
+        /* writing bit 7 resets the shift register and the PRG mode */
+        mmc1.write_prg(0x8000, 1);
+        mmc1.write_prg(0x8000, 0b1000_0000);
+        assert_eq!(mmc1.read_prg(0xC000), 7);
+    }
+
+    #[test]
+    fn test_chr_banking_and_mirroring() {
+        let mut mmc1 = configure_mmc1();
+
+        /* 4KiB CHR mode, vertical mirroring */
+        write_serial(&mut mmc1, 0x8000, 0b1_1110);
+        assert_eq!(mmc1.mirroring(), Mirroring::Vertical);
+        write_serial(&mut mmc1, 0xA000, 3);
+        write_serial(&mut mmc1, 0xC000, 1);
+        assert_eq!(mmc1.read_chr(0x0000), 3);
+        assert_eq!(mmc1.read_chr(0x1000), 1);
+
+        /* 8KiB CHR mode, single screen */
+        write_serial(&mut mmc1, 0x8000, 0b0_1101);
+        assert_eq!(mmc1.mirroring(), Mirroring::SingleScreenUpper);
+        assert_eq!(mmc1.read_chr(0x0000), 2);
+        assert_eq!(mmc1.read_chr(0x1000), 3);
+    }
+
+    #[test]
+    fn test_prg_ram() {
+        let mut mmc1 = configure_mmc1();
+
+        mmc1.write_prg(0x6000, 0x55);
+        assert_eq!(mmc1.read_prg(0x6000), 0x55);
+
+        /* disable PRG-RAM */
+        write_serial(&mut mmc1, 0xE000, 0b1_0000);
+        mmc1.write_prg(0x6000, 0xAA);
+        assert_eq!(mmc1.read_prg(0x6000), 0);
+
+        write_serial(&mut mmc1, 0xE000, 0b0_0000);
+        assert_eq!(mmc1.read_prg(0x6000), 0x55);
+    }
+}
@@ -0,0 +1,422 @@
+use crate::mapper::{Mapper, Mirroring};
+use crate::ram::Ram;
+use crate::rom::{CharacterRom, ProgramRom, Rom};
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x2000; // 8KiB
+const CHR_BANK_SIZE: usize = 0x0400; // 1KiB
+
+/*
+ * A12 has to stay low for about 3 CPU cycles before a rising edge
+ * clocks the scanline counter. This filters out the short pulses
+ * between the sprite pattern fetches on dots 257-320.
+ */
+const A12_FILTER_DOTS: u64 = 9;
+
+/*
+ * Mapper 4: Nintendo MMC3 (TxROM)
+ *
+ * Eight bank registers (R0-R7) are selected with $8000 and written
+ * with $8001. The scanline counter is clocked by rising edges of
+ * PPU address line A12.
+ */
+pub struct Mmc3 {
+    prog_rom: ProgramRom,
+    chr_rom: CharacterRom,
+    chr_ram: Vec<u8>,
+    prg_ram: Ram,
+    four_screen: bool,
+    bank_select: u8,     // $8000
+    banks: [u8; 8],      // $8001
+    mirroring: u8,       // $A000
+    prg_ram_protect: u8, // $A001
+    irq_latch: u8,       // $C000
+    irq_counter: u8,
+    irq_reload: bool,  // $C001
+    irq_enabled: bool, // $E000/$E001
+    irq_pending: bool,
+    a12: bool,
+    a12_low_since: u64,
+}
+
+impl Mmc3 {
+    pub fn new(rom: Rom) -> Mmc3 {
+        let chr_len = rom.chr_rom.data.len();
+        Mmc3 {
+            prog_rom: rom.prog_rom,
+            chr_rom: rom.chr_rom,
+            chr_ram: if chr_len == 0 {
+                vec![0; 0x2000]
+            } else {
+                Vec::new()
+            },
+            prg_ram: Ram::new(0x2000),
+            four_screen: rom.header.mirroring == Mirroring::FourScreen,
+            bank_select: 0,
+            banks: [0, 2, 4, 5, 6, 7, 0, 1],
+            mirroring: 0,
+            prg_ram_protect: 0b1000_0000,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            a12: false,
+            a12_low_since: 0,
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let nbanks = self.prog_rom.data.len() / PRG_BANK_SIZE;
+        let slot = (addr as usize - 0x8000) / PRG_BANK_SIZE;
+        let swapped = (self.bank_select & 0b0100_0000) != 0;
+
+        let bank = match (slot, swapped) {
+            (0, false) | (2, true) => self.banks[6] as usize,
+            (1, _) => self.banks[7] as usize,
+            (0, true) | (2, false) => nbanks - 2,
+            _ => nbanks - 1,
+        };
+
+        (bank % nbanks) * PRG_BANK_SIZE + (addr as usize % PRG_BANK_SIZE)
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        /* A12 inversion swaps the 2KiB and 1KiB halves */
+        let addr = if (self.bank_select & 0b1000_0000) != 0 {
+            addr ^ 0x1000
+        } else {
+            addr
+        } as usize;
+
+        let bank = match addr / CHR_BANK_SIZE {
+            0 => self.banks[0] as usize & !1,
+            1 => self.banks[0] as usize | 1,
+            2 => self.banks[1] as usize & !1,
+            3 => self.banks[1] as usize | 1,
+            n => self.banks[n - 2] as usize,
+        };
+
+        let len = if self.chr_rom.data.is_empty() {
+            self.chr_ram.len()
+        } else {
+            self.chr_rom.data.len()
+        };
+        (bank * CHR_BANK_SIZE + (addr % CHR_BANK_SIZE)) % len
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        (self.prg_ram_protect & 0b1000_0000) != 0
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_enabled() && (self.prg_ram_protect & 0b0100_0000) == 0
+    }
+
+    fn clock_irq_counter(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => self.prg_ram.read(addr - 0x6000),
+            0x8000..=0xFFFF => self.prog_rom.data[self.prg_offset(addr)],
+            _ => 0,
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        let even = (addr & 1) == 0;
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_writable() => {
+                self.prg_ram.write(addr - 0x6000, data);
+            }
+            0x8000..=0x9FFF => {
+                if even {
+                    self.bank_select = data;
+                } else {
+                    let reg = (self.bank_select & 0b0000_0111) as usize;
+                    self.banks[reg] = data;
+                }
+            }
+            0xA000..=0xBFFF => {
+                if even {
+                    self.mirroring = data & 1;
+                } else {
+                    self.prg_ram_protect = data;
+                }
+            }
+            0xC000..=0xDFFF => {
+                if even {
+                    self.irq_latch = data;
+                } else {
+                    self.irq_counter = 0;
+                    self.irq_reload = true;
+                }
+            }
+            0xE000..=0xFFFF => {
+                if even {
+                    /* disabling also acknowledges a pending IRQ */
+                    self.irq_enabled = false;
+                    self.irq_pending = false;
+                } else {
+                    self.irq_enabled = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        let offset = self.chr_offset(addr);
+        if self.chr_rom.data.is_empty() {
+            self.chr_ram[offset]
+        } else {
+            self.chr_rom.data[offset]
+        }
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        /* CHR-ROM is not writable */
+        if self.chr_rom.data.is_empty() {
+            let offset = self.chr_offset(addr);
+            self.chr_ram[offset] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.four_screen {
+            Mirroring::FourScreen
+        } else if self.mirroring == 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        }
+    }
+
+    fn ppu_bus_addr(&mut self, addr: u16, ppu_cycle: u64) {
+        let a12 = (addr & 0x1000) != 0;
+        if a12 && !self.a12 && ppu_cycle - self.a12_low_since >= A12_FILTER_DOTS {
+            self.clock_irq_counter();
+        }
+        if !a12 && self.a12 {
+            self.a12_low_since = ppu_cycle;
+        }
+        self.a12 = a12;
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        self.prg_ram.save_state(w);
+        w.write_bytes(&self.chr_ram);
+        w.write_u8(self.bank_select);
+        w.write_bytes(&self.banks);
+        w.write_u8(self.mirroring);
+        w.write_u8(self.prg_ram_protect);
+        w.write_u8(self.irq_latch);
+        w.write_u8(self.irq_counter);
+        w.write_bool(self.irq_reload);
+        w.write_bool(self.irq_enabled);
+        w.write_bool(self.irq_pending);
+        w.write_bool(self.a12);
+        w.write_u64(self.a12_low_since);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.prg_ram.load_state(r)?;
+        r.read_bytes_into(&mut self.chr_ram)?;
+        self.bank_select = r.read_u8()?;
+        r.read_bytes_into(&mut self.banks)?;
+        self.mirroring = r.read_u8()?;
+        self.prg_ram_protect = r.read_u8()?;
+        self.irq_latch = r.read_u8()?;
+        self.irq_counter = r.read_u8()?;
+        self.irq_reload = r.read_bool()?;
+        self.irq_enabled = r.read_bool()?;
+        self.irq_pending = r.read_bool()?;
+        self.a12 = r.read_bool()?;
+        self.a12_low_since = r.read_u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::RomHeader;
+
+    fn configure_mmc3() -> Mmc3 {
+        /* 16 PRG banks and 16 CHR banks, each filled with its bank number */
+        let prg: Vec<u8> = (0..16 * PRG_BANK_SIZE)
+            .map(|i| (i / PRG_BANK_SIZE) as u8)
+            .collect();
+        let chr: Vec<u8> = (0..16 * CHR_BANK_SIZE)
+            .map(|i| (i / CHR_BANK_SIZE) as u8)
+            .collect();
+
+        Mmc3::new(Rom {
+            header: RomHeader {
+                mapper: 4,
+                ..Default::default()
+            },
+            prog_rom: ProgramRom::new(&prg),
+            chr_rom: CharacterRom::new(&chr),
+        })
+    }
+
+    /* one scanline worth of A12 activity: background at $0000, sprites at $1000 */
+    fn render_scanline(mmc3: &mut Mmc3, cycle: &mut u64) {
+        for dot in 0..341u64 {
+            let addr = match dot {
+                257..=320 if (dot - 257) % 8 >= 4 => 0x1000,
+                _ => 0x0000,
+            };
+            mmc3.ppu_bus_addr(addr, *cycle + dot);
+        }
+        *cycle += 341;
+    }
+
+    #[test]
+    fn test_prg_banking() {
+        let mut mmc3 = configure_mmc3();
+
+        mmc3.write_prg(0x8000, 6);
+        mmc3.write_prg(0x8001, 3);
+        mmc3.write_prg(0x8000, 7);
+        mmc3.write_prg(0x8001, 5);
+        assert_eq!(mmc3.read_prg(0x8000), 3);
+        assert_eq!(mmc3.read_prg(0xA000), 5);
+        assert_eq!(mmc3.read_prg(0xC000), 14);
+        assert_eq!(mmc3.read_prg(0xE000), 15);
+
+        /* swap $8000 and $C000 */
+        mmc3.write_prg(0x8000, 0b0100_0110);
+        assert_eq!(mmc3.read_prg(0x8000), 14);
+        assert_eq!(mmc3.read_prg(0xA000), 5);
+        assert_eq!(mmc3.read_prg(0xC000), 3);
+        assert_eq!(mmc3.read_prg(0xE000), 15);
+    }
+
+    #[test]
+    fn test_chr_banking_and_mirroring() {
+        let mut mmc3 = configure_mmc3();
+
+        for (reg, bank) in [4, 8, 1, 2, 3, 5].iter().enumerate() {
+            mmc3.write_prg(0x8000, reg as u8);
+            mmc3.write_prg(0x8001, *bank);
+        }
+        assert_eq!(mmc3.read_chr(0x0000), 4);
+        assert_eq!(mmc3.read_chr(0x0400), 5);
+        assert_eq!(mmc3.read_chr(0x0800), 8);
+        assert_eq!(mmc3.read_chr(0x0C00), 9);
+        assert_eq!(mmc3.read_chr(0x1000), 1);
+        assert_eq!(mmc3.read_chr(0x1C00), 5);
+
+        /* CHR A12 inversion */
+        mmc3.write_prg(0x8000, 0b1000_0000);
+        assert_eq!(mmc3.read_chr(0x0000), 1);
+        assert_eq!(mmc3.read_chr(0x1000), 4);
+        assert_eq!(mmc3.read_chr(0x1C00), 9);
+
+        mmc3.write_prg(0xA000, 0);
+        assert_eq!(mmc3.mirroring(), Mirroring::Vertical);
+        mmc3.write_prg(0xA000, 1);
+        assert_eq!(mmc3.mirroring(), Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn test_prg_ram_protect() {
+        let mut mmc3 = configure_mmc3();
+
+        mmc3.write_prg(0x6000, 0x55);
+        assert_eq!(mmc3.read_prg(0x6000), 0x55);
+
+        /* write protect */
+        mmc3.write_prg(0xA001, 0b1100_0000);
+        mmc3.write_prg(0x6000, 0xAA);
+        assert_eq!(mmc3.read_prg(0x6000), 0x55);
+
+        /* disable */
+        mmc3.write_prg(0xA001, 0b0000_0000);
+        assert_eq!(mmc3.read_prg(0x6000), 0);
+    }
+
+    #[test]
+    fn test_scanline_irq() {
+        let mut mmc3 = configure_mmc3();
+        let mut cycle = 0;
+
+        mmc3.write_prg(0xC000, 3);
+        mmc3.write_prg(0xC001, 0);
+        mmc3.write_prg(0xE001, 0);
+
+        /* the first clock reloads the counter */
+        for _ in 0..3 {
+            render_scanline(&mut mmc3, &mut cycle);
+            assert!(!mmc3.irq());
+        }
+        render_scanline(&mut mmc3, &mut cycle);
+        assert!(mmc3.irq());
+
+        /* acknowledge */
+        mmc3.write_prg(0xE000, 0);
+        assert!(!mmc3.irq());
+
+        /* a short pulse on A12 is filtered out */
+        mmc3.write_prg(0xC001, 0);
+        mmc3.ppu_bus_addr(0x1000, cycle);
+        assert_eq!(mmc3.irq_counter, 3);
+        mmc3.ppu_bus_addr(0x0000, cycle + 2);
+        mmc3.ppu_bus_addr(0x1000, cycle + 6);
+        assert_eq!(mmc3.irq_counter, 3);
+    }
+
+    #[test]
+    fn test_save_state() {
+        let mut mmc3 = configure_mmc3();
+        let mut cycle = 0;
+
+        mmc3.write_prg(0x8000, 0b0100_0110);
+        mmc3.write_prg(0x8001, 5);
+        mmc3.write_prg(0xA000, 1);
+        mmc3.write_prg(0xA001, 0b1000_0000);
+        mmc3.write_prg(0x6000, 0x55);
+        mmc3.write_prg(0xC000, 1);
+        mmc3.write_prg(0xE001, 0);
+        render_scanline(&mut mmc3, &mut cycle);
+
+        let mut w = StateWriter::new(0);
+        mmc3.save_state(&mut w);
+        let state = w.into_inner();
+
+        let mut restored = configure_mmc3();
+        let mut r = StateReader::new(&state, 0).unwrap();
+        restored.load_state(&mut r).unwrap();
+        assert_eq!(r.finish(), Ok(()));
+
+        assert_eq!(restored.read_prg(0xC000), 5);
+        assert_eq!(restored.read_prg(0x6000), 0x55);
+        assert_eq!(restored.mirroring(), Mirroring::Horizontal);
+
+        /* both count down to the IRQ on the same scanline */
+        let mut restored_cycle = cycle;
+        render_scanline(&mut mmc3, &mut cycle);
+        render_scanline(&mut restored, &mut restored_cycle);
+        assert!(mmc3.irq());
+        assert!(restored.irq());
+    }
+}
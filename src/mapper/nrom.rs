@@ -0,0 +1,81 @@
+use crate::mapper::{Mapper, Mirroring};
+use crate::ram::Ram;
+use crate::rom::{CharacterRom, ProgramRom, Rom};
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+/*
+ * Mapper 0: no bank switching.
+ * 16KiB PRG-ROM is mirrored into $C000-$FFFF.
+ */
+pub struct Nrom {
+    prog_rom: ProgramRom,
+    chr_rom: CharacterRom,
+    chr_ram: Vec<u8>,
+    prg_ram: Ram,
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    pub fn new(rom: Rom) -> Nrom {
+        let chr_len = rom.chr_rom.data.len();
+        Nrom {
+            prog_rom: rom.prog_rom,
+            chr_rom: rom.chr_rom,
+            chr_ram: if chr_len == 0 {
+                vec![0; 0x2000]
+            } else {
+                Vec::new()
+            },
+            prg_ram: Ram::new(0x2000),
+            mirroring: rom.header.mirroring,
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram.read(addr - 0x6000),
+            0x8000..=0xFFFF => {
+                let len = self.prog_rom.data.len();
+                self.prog_rom.read(((addr - 0x8000) as usize % len) as u16)
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        if let 0x6000..=0x7FFF = addr {
+            self.prg_ram.write(addr - 0x6000, data);
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        if self.chr_rom.data.is_empty() {
+            self.chr_ram[addr as usize]
+        } else {
+            self.chr_rom.data[addr as usize]
+        }
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        /* CHR-ROM is not writable */
+        if self.chr_rom.data.is_empty() {
+            self.chr_ram[addr as usize] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        self.prg_ram.save_state(w);
+        w.write_bytes(&self.chr_ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.prg_ram.load_state(r)?;
+        r.read_bytes_into(&mut self.chr_ram)
+    }
+}
@@ -1,41 +1,596 @@
-use crate::cpu::Cpu;
+use crate::apu::Channel;
+use crate::cpu::{Cpu, Tracer};
 use crate::cpu_bus::CpuBus;
+use crate::joypad::{Button, Player};
+use crate::mapper;
 use crate::ppu;
 use crate::ram::Ram;
-use crate::rom;
+use crate::rewind::Rewind;
+use crate::rom::{self, RomHeader};
+use crate::savestate::{self, StateError, StateReader, StateWriter};
+use crate::video::{NullSink, VideoSink};
+use crate::wav::WavSink;
 
+use std::fs;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-pub const CV_WINDOW_TITLE: &str = "Tapioca-NES";
+/* samples written to the WAV files at a time */
+const WAV_CHUNK: usize = 4096;
 
+/// Width of the picture in pixels
+pub const SCREEN_WIDTH: usize = ppu::Ppu::WIDTH;
+/// Height of the picture in pixels
+pub const SCREEN_HEIGHT: usize = ppu::Ppu::HEIGHT;
+
+/// The whole console: CPU, PPU, APU, controllers and the cartridge.
+///
+/// Nothing runs on its own: drive it with run_frame() or
+/// step_instruction(), feed it input with set_button() and collect the
+/// pictures through a VideoSink and the audio with read_samples().
 pub struct Nes {
     cpu: Cpu,
+    cycles: u64, // master clock counted in CPU cycles
+    wav: Option<WavSink>,
+    rom_path: Option<PathBuf>, // save slots are stored next to it
+    rom_hash: u64,             // save states only load into the ROM they were made with
+    header: RomHeader,
+    rewind: Option<Rewind>,
+    video: Box<dyn VideoSink>,
 }
 
 impl Nes {
+    /// Power on with the iNES file at `file_path`. Save slots are stored
+    /// next to it.
     pub fn load<P: AsRef<Path>>(file_path: P) -> io::Result<Nes> {
         let buffer = std::fs::read(file_path.as_ref())?;
+        let mut nes = Nes::from_bytes(buffer)?;
+        nes.rom_path = Some(file_path.as_ref().to_path_buf());
+        Ok(nes)
+    }
 
-        let (prog, chr) = rom::load(buffer)
-            .ok_or_else(|| io::Error::new(std::io::ErrorKind::Other, "Not an NES ROM"))?;
+    /// Power on with an iNES image already in memory. An image that
+    /// cannot be loaded gives an InvalidData error wrapping a RomError.
+    pub fn from_bytes(buffer: Vec<u8>) -> io::Result<Nes> {
+        let rom_hash = savestate::rom_hash(&buffer);
+
+        let rom = rom::load(buffer)?;
+        let header = rom.header.clone();
+        let cartridge = mapper::new(rom)?;
 
         let wram = Ram::new(0x0800);
-        let ppu = ppu::Ppu::new(chr);
+        let ppu = ppu::Ppu::new(cartridge.clone());
 
-        let cpu_bus = CpuBus::new(wram, prog, ppu);
+        let cpu_bus = CpuBus::new(wram, cartridge, ppu);
 
-        Ok(Nes {
+        let mut nes = Nes {
             cpu: Cpu::new(cpu_bus),
-        })
+            cycles: 0,
+            wav: None,
+            rom_path: None,
+            rom_hash,
+            header,
+            rewind: None,
+            video: Box::new(NullSink),
+        };
+        nes.reset();
+        Ok(nes)
+    }
+
+    /// What the header of the ROM says about the cartridge
+    pub fn cartridge_info(&self) -> &RomHeader {
+        &self.header
+    }
+
+    /// Snapshot of the whole machine. The audio not yet read and the
+    /// picture being drawn are not included.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new(self.rom_hash);
+        w.write_u64(self.cycles);
+        self.cpu.save_state(&mut w);
+        w.into_inner()
+    }
+
+    /// Restore a snapshot taken by save_state. The machine is left
+    /// untouched if the state cannot be loaded.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let backup = self.save_state();
+        let result = self.restore_state(data);
+        match result {
+            // the rewind history belongs to another timeline
+            Ok(()) => self.clear_rewind(),
+            Err(_) => self
+                .restore_state(&backup)
+                .expect("failed to restore the machine state"),
+        }
+        result
+    }
+
+    fn restore_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut r = StateReader::new(data, self.rom_hash)?;
+        self.cycles = r.read_u64()?;
+        self.cpu.load_state(&mut r)?;
+        r.finish()
+    }
+
+    /*
+     * Save slots are stored next to the ROM: game.nes.ss0 ... game.nes.ss9
+     */
+    fn slot_path(&self, slot: u8) -> io::Result<PathBuf> {
+        let mut path = match &self.rom_path {
+            Some(path) => path.clone().into_os_string(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "save slots need a ROM file",
+                ))
+            }
+        };
+        path.push(format!(".ss{}", slot));
+        Ok(PathBuf::from(path))
+    }
+
+    /// Write save_state() to `<rom file>.ss<slot>`
+    pub fn save_slot(&mut self, slot: u8) -> io::Result<()> {
+        fs::write(self.slot_path(slot)?, self.save_state())
+    }
+
+    /// Restore the state written by save_slot()
+    pub fn load_slot(&mut self, slot: u8) -> io::Result<()> {
+        let data = fs::read(self.slot_path(slot)?)?;
+        self.load_state(&data)?;
+        Ok(())
+    }
+
+    /// Record the audio output to a WAV file, and with `per_channel` one
+    /// file per channel next to it (out.wav -> out.pulse1.wav, ...)
+    pub fn record_wav<P: AsRef<Path>>(&mut self, path: P, per_channel: bool) -> io::Result<()> {
+        let apu = self.cpu.bus_mut().apu_mut();
+        apu.set_channel_outputs(per_channel);
+        self.wav = Some(WavSink::create(path, apu.sample_rate(), per_channel)?);
+        Ok(())
+    }
+
+    /// Write the audio generated so far to the WAV files, if recording.
+    /// Samples are written in chunks: up to one chunk may be left over
+    /// until finish_wav().
+    pub fn write_wav(&mut self) -> io::Result<()> {
+        self.drain_wav(WAV_CHUNK)
+    }
+
+    /// Write all the audio left and stop recording
+    pub fn finish_wav(&mut self) -> io::Result<()> {
+        self.drain_wav(1)?;
+        self.wav = None;
+        Ok(())
+    }
+
+    /* write chunks while at least `min` samples are waiting */
+    fn drain_wav(&mut self, min: usize) -> io::Result<()> {
+        let apu = self.cpu.bus_mut().apu_mut();
+        let wav = match self.wav.as_mut() {
+            Some(wav) => wav,
+            None => return Ok(()),
+        };
+
+        let mut buf = [0.0; WAV_CHUNK];
+        while apu.samples_available() >= min {
+            let n = apu.read_samples(&mut buf);
+            wav.write_mixed(&buf[..n])?;
+            if wav.per_channel() {
+                for channel in Channel::ALL.iter() {
+                    let n = apu.read_channel_samples(*channel, &mut buf);
+                    wav.write_channel(*channel, &buf[..n])?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep a state every `interval` frames, `capacity` states at most,
+    /// for rewind_frame(). Rewinding is off until this is called; a
+    /// capacity of 0 turns it off again.
+    pub fn set_rewind(&mut self, interval: u64, capacity: usize) {
+        self.rewind = if capacity > 0 {
+            Some(Rewind::new(interval, capacity))
+        } else {
+            None
+        };
+    }
+
+    fn clear_rewind(&mut self) {
+        if let Some(rewind) = self.rewind.as_mut() {
+            rewind.clear();
+        }
+    }
+
+    fn capture_rewind(&mut self) {
+        let frame = self.cpu.bus().ppu().frame();
+        let due = match &self.rewind {
+            Some(rewind) => rewind.due(frame),
+            None => false,
+        };
+        if due {
+            let state = self.save_state();
+            self.rewind.as_mut().unwrap().push(frame, state);
+        }
+    }
+
+    /// Step back one frame: the previous picture is drawn again and the
+    /// emulation goes on from the start of that frame. Returns false when
+    /// there is nothing older to go back to or rewinding is off.
+    pub fn rewind_frame(&mut self) -> bool {
+        let frame = self.cpu.bus().ppu().frame();
+        if frame < 2 {
+            return false;
+        }
+        let state = match self.rewind.as_mut().and_then(|r| r.rewind_to(frame - 2)) {
+            Some((_, state)) => state,
+            None => return false,
+        };
+        self.restore_state(&state)
+            .expect("failed to restore a rewind state");
+
+        // replay up to the previous frame, which draws its picture
+        while self.cpu.bus().ppu().frame() < frame - 1 {
+            self.run_cpu();
+        }
+        self.cpu.bus_mut().ppu_mut().take_frame_ready();
+        self.present();
+        self.capture_rewind();
+        true
+    }
+
+    /// Press the reset button
+    pub fn reset(&mut self) {
+        let cycles = self.cpu.reset();
+        self.clock(cycles);
     }
 
-    pub fn start(&mut self) {
-        opencv::highgui::start_window_thread().unwrap();
-        //pirintln!("{:?}", self.game_rom);
-        self.cpu.reset();
+    /// Run until the PPU enters vblank, i.e. until the next picture is in
+    /// frame_buffer(). Returns the number of CPU cycles executed.
+    pub fn run_frame(&mut self) -> u64 {
+        let start = self.cycles;
         loop {
-            self.cpu.run();
+            self.step_instruction();
+            if self.cpu.bus_mut().ppu_mut().take_frame_ready() {
+                self.present();
+                return self.cycles - start;
+            }
+        }
+    }
+
+    /// Where run_frame() sends the pictures (nowhere by default)
+    pub fn set_video_sink(&mut self, sink: Box<dyn VideoSink>) {
+        self.video = sink;
+    }
+
+    fn present(&mut self) {
+        self.video.present(self.cpu.bus().ppu().frame_buffer());
+    }
+
+    /// Execute one CPU instruction and let the other devices catch up
+    pub fn step_instruction(&mut self) -> usize {
+        let cycles = self.run_cpu();
+        if self.rewind.is_some() {
+            self.capture_rewind();
+        }
+        cycles
+    }
+
+    fn run_cpu(&mut self) -> usize {
+        let cycles = self.cpu.run();
+        self.clock(cycles)
+    }
+
+    /// Call `tracer` with a nestest.log style line before every CPU
+    /// instruction (None turns tracing off)
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.cpu.set_tracer(tracer);
+    }
+
+    /// Press or release a button on the controller of the given player
+    pub fn set_button(&mut self, player: Player, button: Button, pressed: bool) {
+        self.cpu
+            .bus_mut()
+            .joypad_mut(player)
+            .set_button(button, pressed);
+    }
+
+    /// Host sample rate of the audio output (44100Hz by default)
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.cpu.bus_mut().apu_mut().set_sample_rate(sample_rate);
+    }
+
+    /// Pull the audio generated so far: fills out with up to out.len()
+    /// mono samples in -1.0..1.0 and returns how many were written.
+    /// About one second of audio is kept if nobody reads it.
+    pub fn read_samples(&mut self, out: &mut [f32]) -> usize {
+        self.cpu.bus_mut().apu_mut().read_samples(out)
+    }
+
+    /// Also produce a separate audio stream for each channel, to be read
+    /// with read_channel_samples()
+    pub fn set_channel_outputs(&mut self, enabled: bool) {
+        self.cpu.bus_mut().apu_mut().set_channel_outputs(enabled);
+    }
+
+    /// Like read_samples(), for the stream of a single channel. Returns 0
+    /// unless set_channel_outputs(true) was called.
+    pub fn read_channel_samples(&mut self, channel: Channel, out: &mut [f32]) -> usize {
+        self.cpu
+            .bus_mut()
+            .apu_mut()
+            .read_channel_samples(channel, out)
+    }
+
+    /// The last picture: SCREEN_WIDTH x SCREEN_HEIGHT pixels in 24-bit
+    /// RGB, row by row
+    pub fn frame_buffer(&self) -> &[u8] {
+        self.cpu.bus().ppu().frame_buffer()
+    }
+
+    /// The last picture, one NES colour index (0-63) per pixel
+    pub fn indexed_frame_buffer(&self) -> &[u8] {
+        self.cpu.bus().ppu().indexed_frame_buffer()
+    }
+
+    /// The PPU frame counter: the number of the frame being drawn,
+    /// counting from 0 at power on. After run_frame() it numbers the
+    /// picture in frame_buffer(), so it is 0 after the first one. It
+    /// follows the machine back on load_state() and rewind_frame().
+    pub fn frame_count(&self) -> u64 {
+        self.cpu.bus().ppu().frame()
+    }
+
+    /// Number of audio samples read_samples() can return right now
+    pub fn samples_available(&self) -> usize {
+        self.cpu.bus().apu().samples_available()
+    }
+
+    /*
+     * Let the devices catch up with the CPU. Returns the number of cycles
+     * that took, DMC fetches halting the CPU included.
+     */
+    fn clock(&mut self, cpu_cycles: usize) -> usize {
+        let stall = self.cpu.bus_mut().tick(cpu_cycles);
+        self.cpu.stall(stall);
+
+        let cycles = cpu_cycles + stall;
+        self.cycles += cycles as u64;
+        cycles
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video::MemorySink;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /* NROM-128 with `prog` at $C000, its NMI handler at $C008 */
+    fn load_program(prog: &[u8]) -> Nes {
+        let mut prg = vec![0; 0x4000];
+        prg[..prog.len()].copy_from_slice(prog);
+        // NMI, reset and IRQ vectors
+        prg[0x3FFA..].copy_from_slice(&[0x08, 0xC0, 0x00, 0xC0, 0x0A, 0xC0]);
+
+        let mut image = vec![b'N', b'E', b'S', 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        image.extend(prg);
+        image.extend(vec![0; 0x2000]);
+        Nes::from_bytes(image).unwrap()
+    }
+
+    /* enable NMI, then count the NMIs at $00 */
+    fn configure_nes() -> Nes {
+        #[rustfmt::skip]
+        let prog = [0xA9, 0x80,       //C000: LDA #$80
+                    0x8D, 0x00, 0x20, //C002: STA $2000
+                    0x4C, 0x05, 0xC0, //C005: JMP $C005
+                    0xE6, 0x00,       //C008: INC $00
+                    0x40,             //C00A: RTI
+        ];
+        load_program(&prog)
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let mut nes = configure_nes();
+        assert_eq!(nes.step_instruction(), 2); //LDA #$80
+
+        // the first picture is frame 0
+        nes.run_frame();
+        assert_eq!(nes.frame_count(), 0);
+        assert_eq!(
+            nes.frame_buffer().len(),
+            ppu::Ppu::WIDTH * ppu::Ppu::HEIGHT * 3
+        );
+
+        for n in 1..=5 {
+            let cycles = nes.run_frame();
+            // 341 * 262 / 3 CPU cycles, give or take an instruction
+            assert!(cycles > 29770 && cycles < 29790, "{}", cycles);
+            assert_eq!(nes.frame_count(), n);
+            // run_frame returns before the NMI of the new frame
+            assert_eq!(nes.cpu.bus().peek(0x00), n as u8);
+        }
+    }
+
+    #[test]
+    fn test_run_frame_audio() {
+        let mut nes = configure_nes();
+        let mut buf = vec![0.0; 4096];
+
+        nes.run_frame();
+        nes.read_samples(&mut buf);
+        assert_eq!(nes.samples_available(), 0);
+
+        nes.run_frame();
+        // 44100Hz / 60.1Hz
+        let n = nes.samples_available();
+        assert!(n > 720 && n < 750, "{}", n);
+    }
+
+    #[test]
+    fn test_video_sink() {
+        let mut nes = configure_nes();
+        let sink = MemorySink::new();
+        nes.set_video_sink(Box::new(sink.clone()));
+
+        // no rewinding unless asked for
+        for _ in 0..3 {
+            nes.run_frame();
+        }
+        assert!(!nes.rewind_frame());
+        assert_eq!(sink.frame_count(), 3);
+
+        nes.set_rewind(2, 10);
+        for _ in 0..3 {
+            nes.run_frame();
+        }
+        assert_eq!(sink.frame_count(), 6);
+        assert_eq!(sink.last_frame().as_deref(), Some(nes.frame_buffer()));
+
+        // rewinding shows the previous picture again
+        assert!(nes.rewind_frame());
+        assert_eq!(sink.frame_count(), 7);
+        assert_eq!(sink.frame(6), sink.frame(4));
+    }
+
+    #[test]
+    fn test_tracer() {
+        let mut nes = configure_nes();
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let sink = lines.clone();
+        nes.set_tracer(Some(Box::new(move |line: &str| {
+            sink.borrow_mut().push(line.to_string())
+        })));
+
+        nes.step_instruction();
+        nes.step_instruction();
+        nes.set_tracer(None);
+        nes.step_instruction();
+
+        let lines = lines.borrow();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(&lines[0][..16], "C000  A9 80     ");
+        assert_eq!(&lines[1][..16], "C002  8D 00 20  ");
+    }
+
+    #[test]
+    fn test_finish_wav() {
+        let path = std::env::temp_dir().join(format!("nes-test-{}.wav", std::process::id()));
+        let mut nes = configure_nes();
+        nes.record_wav(&path, false).unwrap();
+
+        for _ in 0..3 {
+            nes.run_frame();
+            nes.write_wav().unwrap();
+        }
+        // less than a chunk so far
+        let n = nes.samples_available();
+        assert!(n > 0 && n < WAV_CHUNK, "{}", n);
+
+        nes.finish_wav().unwrap();
+        assert_eq!(nes.samples_available(), 0);
+        let wav = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(wav.len(), 44 + n * 2);
+        assert_eq!(&wav[40..44], &(n as u32 * 2).to_le_bytes());
+    }
+
+    /*
+     * Read PPUSTATUS with the read cycle of LDA $2002 on the given dot of
+     * scanline 241. Returns the value read and the number of NMIs.
+     */
+    fn read_status_at(dot: u16) -> (u8, u8) {
+        #[rustfmt::skip]
+        let prog = [0xAD, 0x02, 0x20, //C000: LDA $2002
+                    0x85, 0x01,       //C003: STA $01
+                    0x4C, 0x05, 0xC0, //C005: JMP $C005
+                    0xE6, 0x00,       //C008: INC $00
+                    0x40,             //C00A: RTI
+        ];
+        let mut nes = load_program(&prog);
+        let ppu = nes.cpu.bus_mut().ppu_mut();
+        ppu.write(ppu::RegType::PPUCTRL, 0b1000_0000);
+        ppu.write(ppu::RegType::PPUMASK, 0);
+
+        // the read is on the 4th cycle, 9 dots after the instruction starts
+        let (scanline, dot) = if dot >= 9 {
+            (241, dot - 9)
+        } else {
+            (240, dot + 341 - 9)
+        };
+        while !(ppu.scanline() == scanline && ppu.dot() == dot) {
+            ppu.step();
+        }
+        for _ in 0..10 {
+            nes.step_instruction();
+        }
+        (nes.cpu.bus().peek(0x01), nes.cpu.bus().peek(0x00))
+    }
+
+    #[test]
+    fn test_vblank_suppression() {
+        // before vblank: read clear, the NMI comes later
+        assert_eq!(read_status_at(0), (0x00, 1));
+        // right before vblank is set: read clear, and no vblank nor NMI
+        assert_eq!(read_status_at(1), (0x00, 0));
+        // after it: read set
+        assert_eq!(read_status_at(4), (0x80, 1));
+    }
+
+    #[test]
+    fn test_dmc_stall() {
+        #[rustfmt::skip]
+        let prog = [0xA9, 0x10,       //C000: LDA #$10
+                    0x8D, 0x15, 0x40, //C002: STA $4015
+                    0xEA,             //C005: NOP
+        ];
+        let mut nes = load_program(&prog);
+        assert_eq!(nes.step_instruction(), 2);
+        // the sample fetch halts the instruction that enables the DMC
+        assert_eq!(nes.step_instruction(), 4 + 4);
+        assert_eq!(nes.step_instruction(), 2);
+    }
+
+    /*
+     * Read the first controller, with A pressed, on the given cycle of an
+     * instruction. The DMC fetches a sample byte on its first cycle.
+     */
+    fn read_joypad_during_fetch(access_cycle: usize) -> u8 {
+        let mut nes = configure_nes();
+        nes.set_button(Player::One, Button::A, true);
+        let bus = nes.cpu.bus_mut();
+        bus.write_by_cpu(0x4016, 1);
+        bus.write_by_cpu(0x4016, 0);
+        bus.write_by_cpu(0x4015, 0x10);
+
+        bus.set_access_cycle(access_cycle);
+        let data = bus.read_by_cpu(0x4016) & 1;
+        assert_eq!(bus.tick(access_cycle + 1), 4);
+        data
+    }
+
+    #[test]
+    fn test_dmc_joypad_conflict() {
+        // fetched before the read
+        assert_eq!(read_joypad_during_fetch(3), 1);
+        // fetched on the read: the halted read clocks out A, B is read
+        assert_eq!(read_joypad_during_fetch(0), 0);
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let mut a = configure_nes();
+        let mut b = configure_nes();
+        for _ in 0..10 {
+            a.run_frame();
+            b.run_frame();
         }
+        assert!(a.frame_buffer() == b.frame_buffer());
+        assert_eq!(a.save_state(), b.save_state());
     }
 }
@@ -1,8 +1,5 @@
-#![allow(dead_code)]
-
-use crate::cpu_bus::NMI_INT;
-use crate::nes;
-use crate::rom::CharacterRom;
+use crate::mapper::{Cartridge, Mirroring};
+use crate::savestate::{StateError, StateReader, StateWriter};
 use bitflags::bitflags;
 use enum_primitive::*;
 
@@ -77,21 +74,29 @@ const PALETTE: [u8; 64*3] =
     0,0,0,
     0,0,0];
 
-pub const SPRITE_WIDTH: usize = 8;
-pub const SPRITE_HEIGHT: usize = 8;
+/* NTSC PPU runs 3 dots per CPU cycle */
+pub const DOTS_PER_CPU_CYCLE: usize = 3;
+
+/* CHR tile decoding for the sprite viewer test */
+#[cfg(all(test, feature = "opencv"))]
+const SPRITE_WIDTH: usize = 8;
+#[cfg(all(test, feature = "opencv"))]
+const SPRITE_HEIGHT: usize = 8;
 
+#[cfg(all(test, feature = "opencv"))]
 #[derive(Copy, Clone, Debug)]
-pub struct Sprite {
+struct Sprite {
     data: [[u8; SPRITE_WIDTH]; SPRITE_HEIGHT],
 }
 
+#[cfg(all(test, feature = "opencv"))]
 impl Sprite {
     fn new(chr: &[u8]) -> Sprite {
         let mut data = [[0u8; SPRITE_WIDTH]; SPRITE_HEIGHT];
 
-        for i in 0..8 {
-            for j in 0..8 {
-                data[i][j] = ((chr[i] & (0b1000_0000 >> j)) != 0) as u8
+        for (i, row) in data.iter_mut().enumerate() {
+            for (j, pixel) in row.iter_mut().enumerate() {
+                *pixel = ((chr[i] & (0b1000_0000 >> j)) != 0) as u8
                     | ((((chr[i + 8] & (0b1000_0000 >> j)) != 0) as u8) << 1);
             }
         }
@@ -100,65 +105,78 @@ impl Sprite {
     }
 }
 
-fn write_sprite(mat: &mut opencv::core::Mat, sprite: &Sprite) {
-    for j in 0..8 {
-        for k in 0..8 {
-            let npalette = sprite.data[j as usize][k as usize] as usize * 3;
-            *mat.at_2d_mut(j, k).unwrap() = opencv::core::Vec3::from([
-                PALETTE[npalette],
-                PALETTE[npalette + 1],
-                PALETTE[npalette + 2],
-            ]);
-        }
-    }
-}
-
 struct Vram {
+    /* CIRAM (plus the cartridge's extra 2KiB for four-screen) */
     pub mem: Vec<u8>,
-    pub chr_rom: CharacterRom,
-    chr_ram: Vec<u8>,
+    palette: [u8; Vram::PALETTE_SIZE],
+    cartridge: Cartridge,
 }
 
 impl Vram {
-    const ADDREE_SIZE: usize = 0x4000;
-    const VRAM_SIZE: usize = 0x2000;
+    const VRAM_SIZE: usize = 0x0800;
     const VRAM_START: usize = 0x2000;
+    const NAMETABLE_SIZE: usize = 0x0400;
+    const PALETTE_SIZE: usize = 0x20;
+
+    fn new(cartridge: Cartridge) -> Self {
+        let size = Self::vram_size(&cartridge);
+        Self {
+            mem: vec![0; size],
+            palette: [0; Self::PALETTE_SIZE],
+            cartridge,
+        }
+    }
 
-    fn new(chr_rom: CharacterRom) -> Self {
-        let chr_len = chr_rom.data.len();
-        let vram = Self {
-            mem: vec![0; Self::VRAM_SIZE],
-            chr_rom,
-            chr_ram: if chr_len == 0 {
-                vec![0; 0x2000]
-            } else {
-                Vec::new()
-            },
-        };
+    fn vram_size(cartridge: &Cartridge) -> usize {
+        match cartridge.borrow().mirroring() {
+            Mirroring::FourScreen => Self::VRAM_SIZE * 2,
+            _ => Self::VRAM_SIZE,
+        }
+    }
 
-        vram
+    /*
+     * $3F00-$3F1F, mirrored up to $3FFF.
+     * $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C.
+     */
+    fn palette_index(addr: u16) -> usize {
+        let index = addr as usize % Self::PALETTE_SIZE;
+        if (index & 0b1_0011) == 0b1_0000 {
+            index & !0b1_0000
+        } else {
+            index
+        }
     }
 
-    fn reset(&mut self) {
-        self.mem = vec![0; Self::VRAM_SIZE];
+    /*
+     * Map one of the four logical nametables ($2000-$2FFF) onto VRAM.
+     * The cartridge decides the mirroring and may switch it at any time.
+     */
+    fn nametable_index(&self, addr: u16) -> usize {
+        let addr = addr as usize - Vram::VRAM_START;
+        let table = (addr / Self::NAMETABLE_SIZE) % 4;
+        let page = match self.cartridge.borrow().mirroring() {
+            /* $2000 = $2400, $2800 = $2C00 */
+            Mirroring::Horizontal => table / 2,
+            /* $2000 = $2800, $2400 = $2C00 */
+            Mirroring::Vertical => table % 2,
+            Mirroring::SingleScreenLower => 0,
+            Mirroring::SingleScreenUpper => 1,
+            Mirroring::FourScreen => table,
+        };
+
+        (page * Self::NAMETABLE_SIZE + addr % Self::NAMETABLE_SIZE) % self.mem.len()
     }
 
     fn read(&self, addr: u16) -> u8 {
         match addr {
-            0x0000..=0x1FFF => {
-                if self.chr_rom.data.len() == 0 {
-                    self.chr_ram[addr as usize]
-                } else {
-                    self.chr_rom.data[addr as usize]
-                }
-            }
+            0x0000..=0x1FFF => self.cartridge.borrow().read_chr(addr),
             /*0x0000..=0x0FFF => {
                 /* pattern table 0 */
             }
             0x1000..=0x1FFF => {
                 /* pattern table 1 */
             }*/
-            0x2000..=0x2FFF => self.mem[addr as usize - Vram::VRAM_START],
+            0x2000..=0x2FFF => self.mem[self.nametable_index(addr)],
             /*0x2000..=0x23BF => {
                 /* name table 0 */
             }
@@ -187,31 +205,32 @@ impl Vram {
                 /* mirror of 0x2000 ..= 0x2EFF */
                 self.read(addr - 0x1000)
             }
-            0x3F00..=0x3F0F => {
-                /* background palette table */
-                unimplemented!();
-            }
-            0x3F10..=0x3F1F => {
-                /* sprite palette table */
-                unimplemented!();
-            }
-            0x3F20..=0x3FFF => {
-                /* mirror of 0x3F00-0x3F1F */
-                panic!("VRAM: read: accessing mirroring area of palette tables");
+            0x3F00..=0x3FFF => {
+                /*
+                 * background palette table (0x3F00-0x3F0F),
+                 * sprite palette table (0x3F10-0x3F1F) and their mirrors
+                 */
+                self.palette[Self::palette_index(addr)]
             }
             _ => panic!("VRAM: Invalid read at 0x{:X}", addr),
         }
     }
 
+    /* the pattern tables are saved with the cartridge */
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.mem);
+        w.write_bytes(&self.palette);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_bytes_into(&mut self.mem)?;
+        r.read_bytes_into(&mut self.palette)
+    }
+
     fn write(&mut self, addr: u16, data: u8) {
-        println!("VRAM: write 0x{:x} at 0x{:x}", data, addr);
         match addr {
             0x0000..=0x1FFF => {
-                if self.chr_rom.data.len() == 0 {
-                    self.chr_ram[addr as usize] = data;
-                } else {
-                    self.chr_rom.data[addr as usize] = data;
-                }
+                self.cartridge.borrow_mut().write_chr(addr, data);
             }
             /*0x0000..=0x0FFF => {
                 /* pattern table 0 */
@@ -220,7 +239,8 @@ impl Vram {
                 /* pattern table 1 */
             }*/
             0x2000..=0x2FFF => {
-                self.mem[addr as usize - Vram::VRAM_START] = data;
+                let index = self.nametable_index(addr);
+                self.mem[index] = data;
             }
             /*0x2000..=0x23BF => {
                 /* name table 0 */
@@ -250,17 +270,9 @@ impl Vram {
                 /* mirror of 0x2000 ..= 0x2EFF */
                 self.write(addr - 0x1000, data);
             }
-            0x3F00..=0x3F0F => {
-                /* background palette table */
-                println!("VRAM: write: bg palette table");
-            }
-            0x3F10..=0x3F1F => {
-                /* sprite palette table */
-                println!("VRAM: write: sprite palette table");
-            }
-            0x3F20..=0x3FFF => {
-                /* mirror of 0x3F00-0x3F1F */
-                panic!("VRAM: write: accessing mirroring area of palette tables");
+            0x3F00..=0x3FFF => {
+                /* palette entries are 6 bits wide */
+                self.palette[Self::palette_index(addr)] = data & 0x3F;
             }
             _ => panic!("VRAM: Invalid write at 0x{:X}", addr),
         }
@@ -282,63 +294,6 @@ enum_from_primitive! {
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-enum PpuPtrState {
-    High,
-    Low,
-}
-
-impl PpuPtrState {
-    fn toggle(&mut self) {
-        match *self {
-            PpuPtrState::High => *self = PpuPtrState::Low,
-            PpuPtrState::Low => *self = PpuPtrState::High,
-        }
-    }
-}
-
-struct PpuPtr {
-    addr: u16,
-    state: PpuPtrState,
-}
-
-impl Default for PpuPtr {
-    fn default() -> PpuPtr {
-        PpuPtr {
-            addr: 0,
-            state: PpuPtrState::High,
-        }
-    }
-}
-
-impl PpuPtr {
-    pub fn new() -> PpuPtr {
-        Default::default()
-    }
-
-    pub fn get(&self) -> u16 {
-        self.addr
-    }
-
-    pub fn get_and_inc(&mut self) -> u16 {
-        let addr = self.addr;
-        self.addr += 1;
-        addr
-    }
-
-    pub fn write(&mut self, addr: u8) {
-        match self.state {
-            PpuPtrState::High => self.addr = (addr as u16) << 8 | (self.addr << 8) >> 8,
-            PpuPtrState::Low => self.addr = (addr as u16) | (self.addr >> 8) << 8,
-        }
-        self.state.toggle();
-    }
-
-    pub fn reset(&mut self) {
-        *self = Default::default();
-    }
-}
-
 struct PpuCtrlReg {
     flags: u8,
 }
@@ -360,6 +315,7 @@ impl PpuCtrlReg {
      * Base nametable address
      * (0 = $2000; 1 = $2400; 2 = $2800; 3 = $2C00)
      */
+    #[cfg(test)]
     fn base_nametable_addr(&self) -> u16 {
         match self.flags & 0b0000_0011 {
             0b0000_0000 => 0x2000,
@@ -414,6 +370,7 @@ impl PpuCtrlReg {
      * PPU master/slave select
      * (0: read backdrop from EXT pins; 1: output color on EXT pins)
      */
+    #[cfg(test)]
     fn ppu_master_slave(&self) -> bool {
         (self.flags & 0b0100_0000) != 0
     }
@@ -441,7 +398,15 @@ bitflags! {
     }
 }
 
-#[derive(Debug)]
+bitflags! {
+    struct PpuStatus: u8 {
+        const SPRITE_OVERFLOW = 0b0010_0000;
+        const SPRITE_ZERO_HIT = 0b0100_0000;
+        const VBLANK          = 0b1000_0000;
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
 struct OamEntry {
     y: u8,
     tile: u8,
@@ -461,123 +426,732 @@ impl OamEntry {
     }
 }
 
+/*
+ * Sprite attributes (byte 2 of an OAM entry)
+ * 76543210
+ * ||||||++- palette (4 to 7)
+ * |||+++--- unimplemented
+ * ||+------ priority (0: in front of background; 1: behind background)
+ * |+------- flip horizontally
+ * +-------- flip vertically
+ */
+fn attr_palette(attr: u8) -> u8 {
+    4 + (attr & 0b0000_0011)
+}
+
+fn attr_behind_background(attr: u8) -> bool {
+    (attr & 0b0010_0000) != 0
+}
+
+fn attr_flip_horizontally(attr: u8) -> bool {
+    (attr & 0b0100_0000) != 0
+}
+
+fn attr_flip_vertically(attr: u8) -> bool {
+    (attr & 0b1000_0000) != 0
+}
+
+/* a sprite selected for the scanline being drawn */
+#[derive(Copy, Clone, Debug)]
+struct LineSprite {
+    x: u8,
+    attr: u8,
+    pattern_lo: u8,
+    pattern_hi: u8,
+}
+
 pub struct Ppu {
     ctrlreg: PpuCtrlReg,
     mask: PpuMask,
-    ppuptr: PpuPtr,
     oamptr: u8,
     sprite_ram: Vec<u8>,
-    vbuf: opencv::core::Mat,
+    /* the picture as NES colour indices (0-63) and as 24-bit RGB, row by row */
+    indices: Vec<u8>,
+    pixels: Vec<u8>,
+    /* vblank started since the last take_frame_ready() */
+    frame_ready: bool,
     vram: Vram,
     last_written: u8,
+    dot: u16,      // 0 ..= 340
+    scanline: u16, // 0 ..= 261 (261: pre-render line)
+    frame: u64,
+    cycles: u64,
+    status: PpuStatus,
+    /* PPUSTATUS was read right before vblank: skip it this frame */
+    suppress_vblank: bool,
+    /*
+     * Internal scroll registers shared by PPUSCROLL and PPUADDR
+     * v: current VRAM address (yyy NN YYYYY XXXXX)
+     * t: temporary VRAM address, the top left of the screen
+     * fine_x: fine X scroll
+     * w: first/second write toggle
+     */
+    v: u16,
+    t: u16,
+    fine_x: u8,
+    w: bool,
+    /* PPUDATA reads lag one access behind */
+    read_buffer: u8,
+    /* background fetch latches */
+    nt_latch: u8,
+    at_latch: u8,
+    pattern_lo_latch: u8,
+    pattern_hi_latch: u8,
+    /* background shift registers: the high byte is the tile being drawn */
+    bg_shift_lo: u16,
+    bg_shift_hi: u16,
+    at_shift_lo: u16,
+    at_shift_hi: u16,
+    /* sprites found for the next scanline and the ones being drawn */
+    secondary_oam: Vec<OamEntry>,
+    line_sprites: Vec<LineSprite>,
+    /* OAM sprite 0 is in secondary_oam / line_sprites (always the first) */
+    sprite_zero_next: bool,
+    sprite_zero_line: bool,
 }
 
 impl Ppu {
-    pub fn new(chr_rom: CharacterRom) -> Ppu {
+    pub fn new(cartridge: Cartridge) -> Ppu {
         Ppu {
             ctrlreg: PpuCtrlReg::new(),
             /* for the lazy ROMs not initializing PPUMASK */
             mask: PpuMask::SHOW_ALL,
-            ppuptr: PpuPtr::new(),
             oamptr: 0,
             sprite_ram: vec![0; 256],
-            vbuf: unsafe {
-                opencv::core::Mat::new_rows_cols(240, 256, opencv::core::CV_8UC3).unwrap()
-            },
-            vram: Vram::new(chr_rom),
+            indices: vec![0; Self::WIDTH * Self::HEIGHT],
+            pixels: vec![0; Self::WIDTH * Self::HEIGHT * 3],
+            frame_ready: false,
+            vram: Vram::new(cartridge),
             last_written: 0,
+            dot: 0,
+            scanline: 0,
+            frame: 0,
+            cycles: 0,
+            status: PpuStatus::empty(),
+            suppress_vblank: false,
+            v: 0,
+            t: 0,
+            fine_x: 0,
+            w: false,
+            read_buffer: 0,
+            nt_latch: 0,
+            at_latch: 0,
+            pattern_lo_latch: 0,
+            pattern_hi_latch: 0,
+            bg_shift_lo: 0,
+            bg_shift_hi: 0,
+            at_shift_lo: 0,
+            at_shift_hi: 0,
+            secondary_oam: Vec::with_capacity(Self::SPRITES_PER_LINE),
+            line_sprites: Vec::with_capacity(Self::SPRITES_PER_LINE),
+            sprite_zero_next: false,
+            sprite_zero_line: false,
         }
     }
 
-    fn show(&mut self) {
-        if !self.mask.intersects(PpuMask::SHOW_ALL) {
+    pub const DOTS_PER_SCANLINE: u16 = 341;
+    pub const SCANLINES_PER_FRAME: u16 = 262;
+    pub const VISIBLE_SCANLINES: u16 = 240;
+    pub const WIDTH: usize = 256;
+    pub const HEIGHT: usize = Self::VISIBLE_SCANLINES as usize;
+    const VBLANK_SCANLINE: u16 = 241;
+    const PRE_RENDER_SCANLINE: u16 = 261;
+    const SPRITES_PER_LINE: usize = 8;
+
+    /*
+     * Advance the PPU by one dot (one PPU clock cycle)
+     */
+    pub fn step(&mut self) {
+        let visible = self.scanline < Self::VISIBLE_SCANLINES;
+        let pre_render = self.scanline == Self::PRE_RENDER_SCANLINE;
+
+        if self.rendering_enabled() && (visible || pre_render) {
+            self.render_dot(visible);
+        }
+
+        if self.scanline == Self::VBLANK_SCANLINE && self.dot == 1 {
+            if !self.suppress_vblank {
+                self.status.insert(PpuStatus::VBLANK);
+            }
+            self.suppress_vblank = false;
+            self.frame_ready = true;
+        }
+        if pre_render && self.dot == 1 {
+            self.status.remove(
+                PpuStatus::VBLANK | PpuStatus::SPRITE_ZERO_HIT | PpuStatus::SPRITE_OVERFLOW,
+            );
+        }
+
+        self.cycles += 1;
+        self.dot += 1;
+
+        /* the pre-render line is one dot shorter on odd frames */
+        if pre_render && self.dot == 340 && (self.frame % 2) == 1 && self.rendering_enabled() {
+            self.dot += 1;
+        }
+
+        if self.dot == Self::DOTS_PER_SCANLINE {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline == Self::SCANLINES_PER_FRAME {
+                self.scanline = 0;
+                self.frame += 1;
+            }
+        }
+    }
+
+    fn rendering_enabled(&self) -> bool {
+        self.mask
+            .intersects(PpuMask::SHOW_BG | PpuMask::SHOW_SPRITES)
+    }
+
+    /*
+     * One dot of a visible or the pre-render scanline
+     */
+    fn render_dot(&mut self, visible: bool) {
+        let dot = self.dot;
+
+        match dot {
+            1..=256 | 321..=336 => {
+                if dot != 1 && dot != 321 {
+                    self.shift_bg();
+                }
+                match (dot - 1) % 8 {
+                    0 => {
+                        self.load_bg_shifters();
+                        self.nt_latch = self.fetch(0x2000 | (self.v & 0x0FFF));
+                    }
+                    2 => {
+                        let v = self.v;
+                        let attr = self
+                            .fetch(0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07));
+                        /* pick the 2x2 tile quadrant */
+                        let shift = ((v >> 4) & 0b100) | (v & 0b010);
+                        self.at_latch = (attr >> shift) & 0b11;
+                    }
+                    4 => self.pattern_lo_latch = self.fetch(self.bg_pattern_addr()),
+                    6 => self.pattern_hi_latch = self.fetch(self.bg_pattern_addr() | 8),
+                    7 => self.increment_x(),
+                    _ => {}
+                }
+            }
+            257..=320 => {
+                if dot == 257 {
+                    self.shift_bg();
+                    self.load_bg_shifters();
+                    self.copy_horizontal();
+                    self.evaluate_sprites(visible);
+                }
+                self.fetch_sprite(dot - 257);
+            }
+            337 | 339 => {
+                if dot == 337 {
+                    self.shift_bg();
+                    self.load_bg_shifters();
+                }
+                /* unused nametable fetches */
+                self.fetch(0x2000 | (self.v & 0x0FFF));
+            }
+            _ => {}
+        }
+
+        if dot == 256 {
+            self.increment_y();
+        }
+        if !visible && (280..=304).contains(&dot) {
+            self.copy_vertical();
+        }
+
+        if visible && (1..=256).contains(&dot) {
+            self.render_pixel(dot - 1);
+        }
+    }
+
+    /* read the PPU bus during rendering */
+    fn fetch(&mut self, addr: u16) -> u8 {
+        self.put_bus_addr(addr);
+        self.vram.read(addr)
+    }
+
+    /* let the cartridge see the PPU address bus */
+    fn put_bus_addr(&mut self, addr: u16) {
+        self.vram
+            .cartridge
+            .borrow_mut()
+            .ppu_bus_addr(addr, self.cycles);
+    }
+
+    fn bg_pattern_addr(&self) -> u16 {
+        let fine_y = (self.v >> 12) & 0b111;
+        self.ctrlreg.bg_pattern_table_addr() + (self.nt_latch as u16) * 16 + fine_y
+    }
+
+    fn shift_bg(&mut self) {
+        self.bg_shift_lo <<= 1;
+        self.bg_shift_hi <<= 1;
+        self.at_shift_lo <<= 1;
+        self.at_shift_hi <<= 1;
+    }
+
+    /* move the latched tile into the low byte of the shift registers */
+    fn load_bg_shifters(&mut self) {
+        self.bg_shift_lo = (self.bg_shift_lo & 0xFF00) | self.pattern_lo_latch as u16;
+        self.bg_shift_hi = (self.bg_shift_hi & 0xFF00) | self.pattern_hi_latch as u16;
+        let fill = |bit: u8| if bit != 0 { 0x00FF } else { 0x0000 };
+        self.at_shift_lo = (self.at_shift_lo & 0xFF00) | fill(self.at_latch & 0b01);
+        self.at_shift_hi = (self.at_shift_hi & 0xFF00) | fill(self.at_latch & 0b10);
+    }
+
+    /* coarse X and the horizontal nametable bit */
+    fn copy_horizontal(&mut self) {
+        self.v = (self.v & !0x041F) | (self.t & 0x041F);
+    }
+
+    /* fine Y, coarse Y and the vertical nametable bit */
+    fn copy_vertical(&mut self) {
+        self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+    }
+
+    fn rendering(&self) -> bool {
+        self.rendering_enabled()
+            && (self.scanline < Self::VISIBLE_SCANLINES
+                || self.scanline == Self::PRE_RENDER_SCANLINE)
+    }
+
+    /*
+     * Step v after a PPUDATA access. While rendering, the access
+     * glitches into a coarse X and a Y increment instead.
+     */
+    fn increment_v(&mut self) {
+        if self.rendering() {
+            self.increment_x();
+            self.increment_y();
+        } else if self.ctrlreg.vram_addr_increment() {
+            self.v = (self.v + 1) & 0x7FFF;
+        } else {
+            self.v = (self.v + 32) & 0x7FFF;
+        }
+    }
+
+    fn increment_x(&mut self) {
+        if (self.v & 0x001F) == 31 {
+            /* wrap into the horizontally adjacent nametable */
+            self.v &= !0x001F;
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    fn increment_y(&mut self) {
+        if (self.v & 0x7000) != 0x7000 {
+            self.v += 0x1000;
             return;
         }
 
-        self.update_whole_vbuf();
+        self.v &= !0x7000;
+        let mut coarse_y = (self.v & 0x03E0) >> 5;
+        if coarse_y == 29 {
+            /* wrap into the vertically adjacent nametable */
+            coarse_y = 0;
+            self.v ^= 0x0800;
+        } else if coarse_y == 31 {
+            /* coarse Y in the attribute table wraps without switching */
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
 
-        let mut screen = opencv::core::Mat::new().unwrap();
+    fn sprite_height(&self) -> u16 {
+        if self.ctrlreg.sprite_size() {
+            16
+        } else {
+            8
+        }
+    }
 
-        opencv::imgproc::resize(
-            &self.vbuf,
-            &mut screen,
-            opencv::core::Size::new(1024, 1024),
-            0.0,
-            0.0,
-            0,
-        )
-        .unwrap();
+    /*
+     * Find the first 8 sprites on the next scanline
+     * (no sprites are drawn on scanline 0)
+     */
+    fn evaluate_sprites(&mut self, visible: bool) {
+        self.secondary_oam.clear();
+        self.sprite_zero_next = false;
+        if !visible {
+            return;
+        }
+
+        let height = self.sprite_height();
+        let scanline = self.scanline;
+        let in_range = |y: u8| scanline.wrapping_sub(y as u16) < height;
+
+        let mut n = 0;
+        while n < 64 && self.secondary_oam.len() < Self::SPRITES_PER_LINE {
+            let entry = OamEntry::new(&self.sprite_ram[(n * 4)..(n * 4 + 4)]);
+            if in_range(entry.y) {
+                self.sprite_zero_next |= n == 0;
+                self.secondary_oam.push(entry);
+            }
+            n += 1;
+        }
 
-        opencv::highgui::imshow(nes::CV_WINDOW_TITLE, &screen).unwrap();
+        /*
+         * Overflow check of the remaining sprites. The hardware increments
+         * the byte offset m along with n, so it reads tile, attribute and
+         * X bytes as Y coordinates (false positives and negatives).
+         */
+        let mut m = 0;
+        while n < 64 {
+            if in_range(self.sprite_ram[n * 4 + m]) {
+                self.status.insert(PpuStatus::SPRITE_OVERFLOW);
+                break;
+            }
+            n += 1;
+            m = (m + 1) % 4;
+        }
     }
 
-    fn update_whole_vbuf(&mut self) {
-        let ntbase = self.ctrlreg.base_nametable_addr() as usize - Vram::VRAM_START;
-        let bg_ptrn_tab_addr = self.ctrlreg.bg_pattern_table_addr();
-        let sprite_ptrn_tab_addr = self.ctrlreg.sprite_pattern_table_addr();
+    /*
+     * Sprite pattern fetches on dots 257-320, 8 dots per sprite.
+     * Empty slots still fetch tile $FF.
+     */
+    fn fetch_sprite(&mut self, cycle: u16) {
+        let slot = (cycle / 8) as usize;
+        let phase = cycle % 8;
 
-        for (i, sprite_index) in (&self.vram.mem[ntbase..(ntbase + 960)]).iter().enumerate() {
-            //println!(
-            //    "get_mat: i={} sprite={}: ({}, {})",
-            //    i,
-            //    sprite,
-            //    ((i % 32) * SPRITE_WIDTH) as i32,
-            //    ((i / 32) * SPRITE_HEIGHT) as i32
-            //);
+        if slot == 0 && phase == 0 {
+            self.line_sprites.clear();
+            self.sprite_zero_line = self.sprite_zero_next;
+        }
 
-            let mut roi = opencv::core::Mat::roi(
-                &self.vbuf,
-                opencv::core::Rect::new(
-                    ((i % 32) * SPRITE_WIDTH) as i32,
-                    ((i / 32) * SPRITE_HEIGHT) as i32,
-                    SPRITE_WIDTH as i32,
-                    SPRITE_HEIGHT as i32,
-                ),
-            )
-            .unwrap();
+        let entry = self.secondary_oam.get(slot).copied();
+        let (tile, attr, row) = match entry {
+            Some(entry) => (
+                entry.tile,
+                entry.attr,
+                self.scanline.wrapping_sub(entry.y as u16),
+            ),
+            None => (0xFF, 0, 0),
+        };
+        let addr = self.sprite_pattern_addr(tile, attr, row);
 
-            let bg_addr = bg_ptrn_tab_addr + (*sprite_index as u16) * 16;
-            let sprite: Vec<u8> = (bg_addr..(bg_addr + 16))
-                .map(|addr| self.vram.read(addr))
-                .collect();
-            assert_eq!(sprite.len(), 16);
+        match phase {
+            /* garbage nametable fetches */
+            0 | 2 => {
+                self.fetch(0x2000 | (self.v & 0x0FFF));
+            }
+            4 => {
+                let mut pattern_lo = self.fetch(addr);
+                if attr_flip_horizontally(attr) {
+                    pattern_lo = pattern_lo.reverse_bits();
+                }
+                if let Some(entry) = entry {
+                    self.line_sprites.push(LineSprite {
+                        x: entry.x,
+                        attr: entry.attr,
+                        pattern_lo,
+                        pattern_hi: 0,
+                    });
+                }
+            }
+            6 => {
+                let mut pattern_hi = self.fetch(addr | 8);
+                if attr_flip_horizontally(attr) {
+                    pattern_hi = pattern_hi.reverse_bits();
+                }
+                if slot < self.line_sprites.len() {
+                    self.line_sprites[slot].pattern_hi = pattern_hi;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /*
+     * Address of the low plane of the given row of a sprite.
+     * 8x16 sprites take the pattern table from bit 0 of the tile index
+     * and use tile & $FE for the top half, tile | 1 for the bottom half.
+     */
+    fn sprite_pattern_addr(&self, tile: u8, attr: u8, row: u16) -> u16 {
+        let height = self.sprite_height();
+        let row = if attr_flip_vertically(attr) {
+            height - 1 - (row % height)
+        } else {
+            row % height
+        };
+
+        if self.ctrlreg.sprite_size() {
+            let table = ((tile & 1) as u16) * 0x1000;
+            let tile = (tile & 0xFE) as u16 + row / 8;
+            table + tile * 16 + (row % 8)
+        } else {
+            self.ctrlreg.sprite_pattern_table_addr() + (tile as u16) * 16 + row
+        }
+    }
+
+    /*
+     * Pick the background or the sprite pixel.
+     * Returns (pixel value, palette).
+     */
+    fn multiplex(bg: (u8, u8), sprite: (u8, u8), behind_background: bool) -> (u8, u8) {
+        let (bg_pixel, _) = bg;
+        let (sprite_pixel, _) = sprite;
 
-            write_sprite(&mut roi, &Sprite::new(&sprite));
+        if sprite_pixel == 0 || (bg_pixel != 0 && behind_background) {
+            bg
+        } else {
+            sprite
+        }
+    }
+
+    /*
+     * Combine background and sprite pixels at (x, scanline)
+     */
+    fn render_pixel(&mut self, x: u16) {
+        let mut bg_pixel = 0;
+        let mut bg_palette = 0;
+        if self.mask.contains(PpuMask::SHOW_BG)
+            && (x >= 8 || self.mask.contains(PpuMask::SHOW_BG_LEFTMOST))
+        {
+            let bit = 0x8000 >> self.fine_x;
+            bg_pixel = ((self.bg_shift_lo & bit) != 0) as u8
+                | ((((self.bg_shift_hi & bit) != 0) as u8) << 1);
+            bg_palette = ((self.at_shift_lo & bit) != 0) as u8
+                | ((((self.at_shift_hi & bit) != 0) as u8) << 1);
         }
 
-        for i in (0..255).step_by(4) {
-            let entry = OamEntry::new(&self.sprite_ram[i..(i + 4)]);
-            if entry.y == 0 {
-                continue;
+        let mut sprite_pixel = 0;
+        let mut sprite_palette = 0;
+        let mut behind_background = false;
+        if self.mask.contains(PpuMask::SHOW_SPRITES)
+            && (x >= 8 || self.mask.contains(PpuMask::SHOW_SPRITES_LEFTMOST))
+        {
+            for (i, sprite) in self.line_sprites.iter().enumerate() {
+                let column = x.wrapping_sub(sprite.x as u16);
+                if column >= 8 {
+                    continue;
+                }
+                let bit = 0b1000_0000 >> column;
+                let pixel = ((sprite.pattern_lo & bit) != 0) as u8
+                    | ((((sprite.pattern_hi & bit) != 0) as u8) << 1);
+                if pixel == 0 {
+                    continue;
+                }
+
+                /* sprite 0 hit: never at x=255 */
+                if i == 0 && self.sprite_zero_line && bg_pixel != 0 && x != 255 {
+                    self.status.insert(PpuStatus::SPRITE_ZERO_HIT);
+                }
+
+                /*
+                 * The first opaque sprite in OAM order wins, even if it is
+                 * behind the background and a later one is not
+                 */
+                sprite_pixel = pixel;
+                sprite_palette = attr_palette(sprite.attr);
+                behind_background = attr_behind_background(sprite.attr);
+                break;
             }
+        }
 
-            let mut roi = opencv::core::Mat::roi(
-                &self.vbuf,
-                opencv::core::Rect::new(
-                    entry.x as i32 * SPRITE_WIDTH as i32,
-                    entry.y as i32 * SPRITE_HEIGHT as i32,
-                    SPRITE_WIDTH as i32,
-                    SPRITE_HEIGHT as i32,
-                ),
-            )
-            .unwrap();
+        let (pixel, palette) = Self::multiplex(
+            (bg_pixel, bg_palette),
+            (sprite_pixel, sprite_palette),
+            behind_background,
+        );
 
-            let sprite_addr = sprite_ptrn_tab_addr + (entry.tile as u16) * 16;
-            let sprite: Vec<u8> = (sprite_addr..(sprite_addr + 16))
-                .map(|addr| self.vram.read(addr))
-                .collect();
-            assert_eq!(sprite.len(), 16);
+        let color = self.palette_color(palette, pixel);
+        self.put_pixel(x, self.scanline, color);
+    }
+
+    /*
+     * Master palette index of a pixel value in palette 0-3 (background)
+     * or 4-7 (sprites). Pixel value 0 is always the backdrop color at $3F00.
+     */
+    fn palette_color(&self, palette: u8, pixel: u8) -> u8 {
+        let addr = if pixel == 0 {
+            0x3F00
+        } else {
+            0x3F00 + (palette as u16) * 4 + pixel as u16
+        };
+
+        let color = self.vram.read(addr);
+        if self.mask.contains(PpuMask::GRAYSCALE) {
+            color & 0x30
+        } else {
+            color
+        }
+    }
+
+    fn put_pixel(&mut self, x: u16, y: u16, color: u8) {
+        let npalette = (color & 0x3F) as usize * 3;
+        let offset = y as usize * Self::WIDTH + x as usize;
+        self.indices[offset] = color & 0x3F;
+        self.pixels[offset * 3..offset * 3 + 3].copy_from_slice(&PALETTE[npalette..npalette + 3]);
+    }
 
-            write_sprite(&mut roi, &Sprite::new(&sprite));
+    /*
+     * Level of the /NMI output (true: asserted).
+     * Enabling NMI in PPUCTRL during vblank asserts it immediately.
+     */
+    pub fn nmi_output(&self) -> bool {
+        self.status.contains(PpuStatus::VBLANK) && self.ctrlreg.generate_nmi()
+    }
+
+    pub fn dot(&self) -> u16 {
+        self.dot
+    }
+
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /*
+     * The last picture drawn: WIDTH x HEIGHT pixels, 3 bytes (RGB) each
+     */
+    pub fn frame_buffer(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    /*
+     * The same picture, one NES colour index (0-63) per pixel
+     */
+    pub fn indexed_frame_buffer(&self) -> &[u8] {
+        &self.indices
+    }
+
+    /*
+     * Whether a picture was completed (vblank started) since the last call
+     */
+    pub fn take_frame_ready(&mut self) -> bool {
+        std::mem::replace(&mut self.frame_ready, false)
+    }
+
+    /*
+     * The frame buffer is not saved: it is redrawn by the next frame
+     */
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.ctrlreg.flags);
+        w.write_u8(self.mask.bits());
+        w.write_u8(self.oamptr);
+        w.write_bytes(&self.sprite_ram);
+        self.vram.save_state(w);
+        w.write_u8(self.last_written);
+        w.write_u16(self.dot);
+        w.write_u16(self.scanline);
+        w.write_u64(self.frame);
+        w.write_u64(self.cycles);
+        w.write_u8(self.status.bits());
+        w.write_bool(self.suppress_vblank);
+        w.write_u16(self.v);
+        w.write_u16(self.t);
+        w.write_u8(self.fine_x);
+        w.write_bool(self.w);
+        w.write_u8(self.read_buffer);
+        w.write_u8(self.nt_latch);
+        w.write_u8(self.at_latch);
+        w.write_u8(self.pattern_lo_latch);
+        w.write_u8(self.pattern_hi_latch);
+        w.write_u16(self.bg_shift_lo);
+        w.write_u16(self.bg_shift_hi);
+        w.write_u16(self.at_shift_lo);
+        w.write_u16(self.at_shift_hi);
+
+        w.write_u8(self.secondary_oam.len() as u8);
+        for entry in &self.secondary_oam {
+            w.write_bytes(&[entry.y, entry.tile, entry.attr, entry.x]);
+        }
+        w.write_u8(self.line_sprites.len() as u8);
+        for sprite in &self.line_sprites {
+            w.write_bytes(&[sprite.x, sprite.attr, sprite.pattern_lo, sprite.pattern_hi]);
         }
+        w.write_bool(self.sprite_zero_next);
+        w.write_bool(self.sprite_zero_line);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.ctrlreg.set(r.read_u8()?);
+        self.mask = PpuMask::from_bits_truncate(r.read_u8()?);
+        self.oamptr = r.read_u8()?;
+        r.read_bytes_into(&mut self.sprite_ram)?;
+        self.vram.load_state(r)?;
+        self.last_written = r.read_u8()?;
+        self.dot = r.read_u16()?;
+        self.scanline = r.read_u16()?;
+        if self.dot >= Self::DOTS_PER_SCANLINE || self.scanline >= Self::SCANLINES_PER_FRAME {
+            return Err(StateError::Corrupted("PPU position"));
+        }
+        self.frame = r.read_u64()?;
+        self.cycles = r.read_u64()?;
+        self.status = PpuStatus::from_bits_truncate(r.read_u8()?);
+        self.suppress_vblank = r.read_bool()?;
+        self.v = r.read_u16()? & 0x7FFF;
+        self.t = r.read_u16()? & 0x7FFF;
+        self.fine_x = r.read_u8()? & 0b0111;
+        self.w = r.read_bool()?;
+        self.read_buffer = r.read_u8()?;
+        self.nt_latch = r.read_u8()?;
+        self.at_latch = r.read_u8()?;
+        self.pattern_lo_latch = r.read_u8()?;
+        self.pattern_hi_latch = r.read_u8()?;
+        self.bg_shift_lo = r.read_u16()?;
+        self.bg_shift_hi = r.read_u16()?;
+        self.at_shift_lo = r.read_u16()?;
+        self.at_shift_hi = r.read_u16()?;
+
+        let mut entry = [0; 4];
+        let count = r.read_u8()? as usize;
+        if count > Self::SPRITES_PER_LINE {
+            return Err(StateError::Corrupted("too many sprites on a line"));
+        }
+        self.secondary_oam.clear();
+        for _ in 0..count {
+            r.read_bytes_into(&mut entry)?;
+            self.secondary_oam.push(OamEntry::new(&entry));
+        }
+        let count = r.read_u8()? as usize;
+        if count > Self::SPRITES_PER_LINE {
+            return Err(StateError::Corrupted("too many sprites on a line"));
+        }
+        self.line_sprites.clear();
+        for _ in 0..count {
+            r.read_bytes_into(&mut entry)?;
+            self.line_sprites.push(LineSprite {
+                x: entry[0],
+                attr: entry[1],
+                pattern_lo: entry[2],
+                pattern_hi: entry[3],
+            });
+        }
+        self.sprite_zero_next = r.read_bool()?;
+        self.sprite_zero_line = r.read_bool()?;
+        self.frame_ready = false;
+        Ok(())
     }
 
     pub fn read(&mut self, regtype: RegType) -> u8 {
-        println!("PPU: read: {:?}", regtype);
         match regtype {
             RegType::PPUSTATUS => {
-                /* our vblank always ready for now :) */
-                0b1000_0000 | (self.last_written & 0b0001_1111)
+                /* the low bits are whatever was last on the PPU data bus */
+                let data = self.status.bits() | (self.last_written & 0b0001_1111);
+
+                /*
+                 * Reading right before vblank starts returns it clear
+                 * and prevents it (and its NMI) for this frame
+                 */
+                if self.scanline == Self::VBLANK_SCANLINE && self.dot == 1 {
+                    self.suppress_vblank = true;
+                }
+
+                self.status.remove(PpuStatus::VBLANK);
+                self.w = false;
+                data
             }
             RegType::OAMDATA => {
                 let addr = self.oamptr;
@@ -586,42 +1160,38 @@ impl Ppu {
                 self.sprite_ram[addr as usize]
             }
             RegType::PPUDATA => {
-                let addr = if self.ctrlreg.vram_addr_increment() {
-                    self.ppuptr.get_and_inc()
+                let addr = self.v & 0x3FFF;
+                self.put_bus_addr(addr);
+
+                let data = self.vram.read(addr);
+                let data = if addr >= 0x3F00 {
+                    /* palette reads are immediate, the buffer gets the nametable underneath */
+                    self.read_buffer = self.vram.read(addr - 0x1000);
+                    data
                 } else {
-                    self.ppuptr.get()
+                    std::mem::replace(&mut self.read_buffer, data)
                 };
 
-                self.vram.read(addr)
+                self.increment_v();
+                data
             }
-            _ => panic!("PPU: Trying to read write-only register: {:?}", regtype),
+            /* write-only registers read back the PPU data bus */
+            _ => self.last_written,
         }
     }
 
     pub fn write(&mut self, regtype: RegType, data: u8) {
-        println!("PPU: write: {:?}: {:x}", regtype, data);
         self.last_written = data;
 
-        if self.ctrlreg.generate_nmi() {
-            *NMI_INT.borrow_mut() = true;
-        }
-
         match regtype {
             RegType::PPUCTRL => {
                 self.ctrlreg.set(data);
-                println!(
-                    "PPUCTRL: write: sprite pattern table addr: 0x{:x}",
-                    self.ctrlreg.sprite_pattern_table_addr()
-                );
-                println!(
-                    "PPUCTRL: write: bg pattern table addr: 0x{:x}",
-                    self.ctrlreg.bg_pattern_table_addr()
-                );
+                /* base nametable */
+                self.t = (self.t & !0x0C00) | (((data & 0b11) as u16) << 10);
             }
             RegType::PPUMASK => {
                 /* use unwrap() cuz all bits correspond to flags */
                 self.mask = PpuMask::from_bits(data).unwrap();
-                self.show();
             }
             RegType::OAMADDR => {
                 self.oamptr = data;
@@ -630,42 +1200,48 @@ impl Ppu {
                 let addr = self.oamptr;
                 self.oamptr = (self.oamptr as u16 + 1) as u8;
 
-                let addr = addr as usize;
-                self.sprite_ram[addr] = data;
-
-                let nsprite = addr / 4;
-                let sprite_begin = nsprite * 4;
-
-                println!(
-                    "OAMDATA: write: {:?}",
-                    OamEntry::new(&self.sprite_ram[sprite_begin..(sprite_begin + 4)])
-                );
-
-                self.show();
+                self.sprite_ram[addr as usize] = data;
             }
             RegType::PPUSCROLL => {
-                //unimplemented!();
+                if !self.w {
+                    /* coarse X and fine X */
+                    self.t = (self.t & !0x001F) | (data >> 3) as u16;
+                    self.fine_x = data & 0b111;
+                } else {
+                    /* coarse Y and fine Y */
+                    self.t = (self.t & !0x73E0)
+                        | (((data & 0b111) as u16) << 12)
+                        | (((data & 0b1111_1000) as u16) << 2);
+                }
+                self.w = !self.w;
             }
             RegType::PPUADDR => {
-                self.ppuptr.write(data);
+                if !self.w {
+                    /* the high byte also clears bit 14 */
+                    self.t = (self.t & 0x00FF) | (((data & 0b0011_1111) as u16) << 8);
+                } else {
+                    self.t = (self.t & 0xFF00) | data as u16;
+                    self.v = self.t;
+                    /* the complete address goes out on the bus */
+                    self.put_bus_addr(self.v & 0x3FFF);
+                }
+                self.w = !self.w;
             }
             RegType::PPUDATA => {
-                let addr = if self.ctrlreg.vram_addr_increment() {
-                    self.ppuptr.get_and_inc()
-                } else {
-                    self.ppuptr.get()
-                };
-
+                let addr = self.v & 0x3FFF;
+                self.put_bus_addr(addr);
                 self.vram.write(addr, data);
-                self.show();
+                self.increment_v();
             }
             _ => panic!("PPU: Trying to write read-only register: {:?}", regtype),
         }
     }
 }
 
+#[cfg(feature = "opencv")]
 #[test]
 fn sprite_test() {
+    use crate::mapper;
     use crate::rom;
     use opencv::prelude::*;
 
@@ -674,10 +1250,10 @@ fn sprite_test() {
     //    std::fs::read("~/Documents/fc3_full_win32_20190611/fc3_full_win32_20190611/marioBros3.nes")
     //        .unwrap();
 
-    let (_, chr_rom) = rom::load(buffer).unwrap();
-    println!("chr rom size: {}", chr_rom.data.len());
+    let rom = rom::load(buffer).unwrap();
+    println!("chr rom size: {}", rom.chr_rom.data.len());
 
-    let ppu = Ppu::new(chr_rom);
+    let ppu = Ppu::new(mapper::new(rom).unwrap());
 
     let title = "Sprite";
 
@@ -692,11 +1268,16 @@ fn sprite_test() {
     'outer: for i in 0..count {
         for j in 0..count {
             let index = (j + i * count) as usize;
-            if index >= ppu.vram.sprites.len() {
+            /* 512 tiles in the pattern tables */
+            if index >= 0x2000 / 16 {
                 break 'outer;
             }
 
-            let sprite = ppu.vram.sprites[index];
+            let sprite_addr = (index * 16) as u16;
+            let chr: Vec<u8> = (sprite_addr..(sprite_addr + 16))
+                .map(|addr| ppu.vram.read(addr))
+                .collect();
+            let sprite = Sprite::new(&chr);
 
             let mut img =
                 unsafe { opencv::core::Mat::new_rows_cols(8, 8, opencv::core::CV_8UC3).unwrap() };
@@ -761,21 +1342,21 @@ fn sprite_test() {
 fn ppu_ctrl_reg_test() {
     let ctrlreg1 = PpuCtrlReg::new();
     assert_eq!(ctrlreg1.base_nametable_addr(), 0x2000);
-    assert_eq!(ctrlreg1.vram_addr_increment(), true);
+    assert!(ctrlreg1.vram_addr_increment());
     assert_eq!(ctrlreg1.sprite_pattern_table_addr(), 0x0000);
     assert_eq!(ctrlreg1.bg_pattern_table_addr(), 0x0000);
-    assert_eq!(ctrlreg1.sprite_size(), false);
-    assert_eq!(ctrlreg1.ppu_master_slave(), false);
-    assert_eq!(ctrlreg1.generate_nmi(), false);
+    assert!(!ctrlreg1.sprite_size());
+    assert!(!ctrlreg1.ppu_master_slave());
+    assert!(!ctrlreg1.generate_nmi());
 
     let ctrlreg2 = PpuCtrlReg::from_u8(0x8);
     assert_eq!(ctrlreg2.base_nametable_addr(), 0x2000);
-    assert_eq!(ctrlreg2.vram_addr_increment(), true);
+    assert!(ctrlreg2.vram_addr_increment());
     assert_eq!(ctrlreg2.sprite_pattern_table_addr(), 0x1000);
     assert_eq!(ctrlreg2.bg_pattern_table_addr(), 0x0000);
-    assert_eq!(ctrlreg2.sprite_size(), false);
-    assert_eq!(ctrlreg2.ppu_master_slave(), false);
-    assert_eq!(ctrlreg2.generate_nmi(), false);
+    assert!(!ctrlreg2.sprite_size());
+    assert!(!ctrlreg2.ppu_master_slave());
+    assert!(!ctrlreg2.generate_nmi());
 }
 
 #[test]
@@ -791,3 +1372,401 @@ fn ppu_mask_test() {
     assert!(!mask1.contains(PpuMask::EMPHASIZE_GREEN));
     assert!(!mask1.contains(PpuMask::EMPHASIZE_BLUE));
 }
+
+#[cfg(test)]
+fn test_cartridge(mapper: u16, mirroring: Mirroring) -> Cartridge {
+    use crate::mapper;
+    use crate::rom;
+
+    mapper::new(rom::Rom {
+        header: rom::RomHeader {
+            mapper,
+            mirroring,
+            ..Default::default()
+        },
+        prog_rom: rom::ProgramRom::new(&[0; 0x8000]),
+        chr_rom: rom::CharacterRom::new(&[]),
+    })
+    .unwrap()
+}
+
+#[test]
+fn vram_mirroring_test() {
+    let mut vram = Vram::new(test_cartridge(0, Mirroring::Vertical));
+    vram.write(0x2000, 0x11);
+    vram.write(0x2400, 0x22);
+    assert_eq!(vram.read(0x2800), 0x11);
+    assert_eq!(vram.read(0x2C00), 0x22);
+    assert_eq!(vram.read(0x3000), 0x11);
+
+    let mut vram = Vram::new(test_cartridge(0, Mirroring::Horizontal));
+    vram.write(0x2000, 0x11);
+    vram.write(0x2800, 0x22);
+    assert_eq!(vram.read(0x2400), 0x11);
+    assert_eq!(vram.read(0x2C00), 0x22);
+
+    let mut vram = Vram::new(test_cartridge(0, Mirroring::FourScreen));
+    assert_eq!(vram.mem.len(), 0x1000);
+    for (i, addr) in [0x2000, 0x2400, 0x2800, 0x2C00].iter().enumerate() {
+        vram.write(*addr, i as u8);
+    }
+    for (i, addr) in [0x2000, 0x2400, 0x2800, 0x2C00].iter().enumerate() {
+        assert_eq!(vram.read(*addr), i as u8);
+    }
+}
+
+#[test]
+fn vram_mirroring_switch_test() {
+    let cartridge = test_cartridge(1, Mirroring::Horizontal);
+    let mut vram = Vram::new(cartridge.clone());
+    let set_mmc1_control = |value: u8| {
+        for i in 0..5 {
+            cartridge.borrow_mut().write_prg(0x8000, (value >> i) & 1);
+        }
+    };
+
+    /* one-screen, lower bank */
+    set_mmc1_control(0b0_1100);
+    vram.write(0x2000, 0x11);
+    assert_eq!(vram.read(0x2400), 0x11);
+    assert_eq!(vram.read(0x2C00), 0x11);
+
+    /* one-screen, upper bank */
+    set_mmc1_control(0b0_1101);
+    vram.write(0x2000, 0x22);
+    assert_eq!(vram.read(0x2800), 0x22);
+
+    /* vertical: both banks are visible */
+    set_mmc1_control(0b0_1110);
+    assert_eq!(vram.read(0x2000), 0x11);
+    assert_eq!(vram.read(0x2400), 0x22);
+}
+
+#[test]
+fn palette_ram_test() {
+    let mut vram = Vram::new(test_cartridge(0, Mirroring::Horizontal));
+    for i in 0..0x20 {
+        vram.write(0x3F00 + i, i as u8);
+    }
+
+    /* the sprite backdrop entries mirror the background ones */
+    assert_eq!(vram.read(0x3F00), 0x10);
+    assert_eq!(vram.read(0x3F04), 0x14);
+    assert_eq!(vram.read(0x3F08), 0x18);
+    assert_eq!(vram.read(0x3F0C), 0x1C);
+    assert_eq!(vram.read(0x3F01), 0x01);
+    assert_eq!(vram.read(0x3F11), 0x11);
+
+    /* $3F20-$3FFF mirrors $3F00-$3F1F */
+    assert_eq!(vram.read(0x3F25), 0x05);
+    assert_eq!(vram.read(0x3FFF), 0x1F);
+
+    vram.write(0x3F10, 0xFF);
+    assert_eq!(vram.read(0x3F00), 0x3F);
+}
+
+#[test]
+fn ppu_vblank_timing_test() {
+    let mut ppu = Ppu::new(test_cartridge(0, Mirroring::Horizontal));
+    ppu.mask = PpuMask::empty();
+
+    /* scanline 241, dot 1 */
+    for _ in 0..(241 * 341 + 1) {
+        ppu.step();
+    }
+    assert!(!ppu.status.contains(PpuStatus::VBLANK));
+    ppu.step();
+    assert!(ppu.status.contains(PpuStatus::VBLANK));
+
+    /* cleared on dot 1 of the pre-render line */
+    while !(ppu.scanline() == 261 && ppu.dot() == 2) {
+        ppu.step();
+    }
+    assert!(!ppu.status.contains(PpuStatus::VBLANK));
+}
+
+#[test]
+fn ppu_odd_frame_skip_test() {
+    let mut ppu = Ppu::new(test_cartridge(0, Mirroring::Horizontal));
+
+    let mut dots = 0;
+    while ppu.frame() == 0 {
+        ppu.step();
+        dots += 1;
+    }
+    assert_eq!(dots, 341 * 262);
+
+    /* odd frames skip one dot while rendering */
+    dots = 0;
+    while ppu.frame() == 1 {
+        ppu.step();
+        dots += 1;
+    }
+    assert_eq!(dots, 341 * 262 - 1);
+}
+
+#[test]
+fn ppu_scroll_increment_test() {
+    let mut ppu = Ppu::new(test_cartridge(0, Mirroring::Horizontal));
+
+    /* coarse X 31 wraps into the next nametable */
+    ppu.v = 0x001F;
+    ppu.increment_x();
+    assert_eq!(ppu.v, 0x0400);
+
+    /* fine Y 7 carries into coarse Y */
+    ppu.v = 0x7000;
+    ppu.increment_y();
+    assert_eq!(ppu.v, 0x0020);
+
+    /* coarse Y 29 wraps into the next nametable */
+    ppu.v = 0x7000 | (29 << 5);
+    ppu.increment_y();
+    assert_eq!(ppu.v, 0x0800);
+
+    /* coarse Y 31 wraps without switching */
+    ppu.v = 0x7000 | (31 << 5);
+    ppu.increment_y();
+    assert_eq!(ppu.v, 0x0000);
+}
+
+#[test]
+fn ppu_scroll_registers_test() {
+    let mut ppu = Ppu::new(test_cartridge(0, Mirroring::Horizontal));
+    ppu.mask = PpuMask::empty();
+
+    ppu.write(RegType::PPUCTRL, 0b0000_0011);
+    assert_eq!(ppu.t, 0x0C00);
+
+    ppu.read(RegType::PPUSTATUS);
+    ppu.write(RegType::PPUSCROLL, 0b0111_1101);
+    assert_eq!(ppu.t, 0x0C0F);
+    assert_eq!(ppu.fine_x, 0b101);
+    assert!(ppu.w);
+
+    ppu.write(RegType::PPUSCROLL, 0b0101_1110);
+    assert_eq!(ppu.t, 0x6D6F);
+    assert!(!ppu.w);
+
+    /* PPUADDR shares t and the write toggle */
+    ppu.write(RegType::PPUADDR, 0b0011_1101);
+    assert_eq!(ppu.t, 0x3D6F);
+    assert!(ppu.w);
+
+    /* reading PPUSTATUS resets the toggle */
+    ppu.read(RegType::PPUSTATUS);
+    ppu.write(RegType::PPUADDR, 0x21);
+    ppu.write(RegType::PPUADDR, 0x08);
+    assert_eq!(ppu.v, 0x2108);
+    assert_eq!(ppu.t, 0x2108);
+}
+
+#[test]
+fn ppu_data_access_test() {
+    let mut ppu = Ppu::new(test_cartridge(0, Mirroring::Horizontal));
+    ppu.mask = PpuMask::empty();
+
+    ppu.write(RegType::PPUADDR, 0x20);
+    ppu.write(RegType::PPUADDR, 0x00);
+    ppu.write(RegType::PPUDATA, 0x11);
+    ppu.write(RegType::PPUDATA, 0x22);
+    assert_eq!(ppu.v, 0x2002);
+
+    /* increment by 32 */
+    ppu.write(RegType::PPUCTRL, 0b0000_0100);
+    ppu.write(RegType::PPUDATA, 0x33);
+    assert_eq!(ppu.v, 0x2022);
+    assert_eq!(ppu.vram.read(0x2002), 0x33);
+
+    /* reads are buffered */
+    ppu.write(RegType::PPUCTRL, 0);
+    ppu.write(RegType::PPUADDR, 0x20);
+    ppu.write(RegType::PPUADDR, 0x00);
+    ppu.read(RegType::PPUDATA);
+    assert_eq!(ppu.read(RegType::PPUDATA), 0x11);
+    assert_eq!(ppu.read(RegType::PPUDATA), 0x22);
+
+    /* except for the palette */
+    ppu.write(RegType::PPUADDR, 0x3F);
+    ppu.write(RegType::PPUADDR, 0x00);
+    ppu.write(RegType::PPUDATA, 0x0F);
+    ppu.write(RegType::PPUADDR, 0x3F);
+    ppu.write(RegType::PPUADDR, 0x00);
+    assert_eq!(ppu.read(RegType::PPUDATA), 0x0F);
+}
+
+#[cfg(test)]
+fn step_to(ppu: &mut Ppu, scanline: u16, dot: u16) {
+    while !(ppu.scanline() == scanline && ppu.dot() == dot) {
+        ppu.step();
+    }
+}
+
+#[test]
+fn ppu_frame_buffer_test() {
+    let mut ppu = Ppu::new(test_cartridge(0, Mirroring::Horizontal));
+
+    /* blank pattern tables: only the backdrop colour shows */
+    ppu.write(RegType::PPUADDR, 0x3F);
+    ppu.write(RegType::PPUADDR, 0x00);
+    ppu.write(RegType::PPUDATA, 0x21);
+
+    step_to(&mut ppu, 240, 0);
+    assert!(!ppu.take_frame_ready());
+    step_to(&mut ppu, 241, 2);
+    assert!(ppu.take_frame_ready());
+    assert!(!ppu.take_frame_ready());
+
+    assert_eq!(ppu.indexed_frame_buffer().len(), Ppu::WIDTH * Ppu::HEIGHT);
+    assert!(ppu.indexed_frame_buffer().iter().all(|&c| c == 0x21));
+    assert_eq!(ppu.frame_buffer().len(), Ppu::WIDTH * Ppu::HEIGHT * 3);
+    for px in ppu.frame_buffer().chunks(3) {
+        assert_eq!(px, &PALETTE[0x21 * 3..0x21 * 3 + 3]);
+    }
+}
+
+#[test]
+fn ppu_status_vblank_test() {
+    let mut ppu = Ppu::new(test_cartridge(0, Mirroring::Horizontal));
+    ppu.mask = PpuMask::empty();
+
+    /* cleared by reading */
+    step_to(&mut ppu, 241, 10);
+    assert_eq!(ppu.read(RegType::PPUSTATUS) & 0x80, 0x80);
+    assert_eq!(ppu.read(RegType::PPUSTATUS) & 0x80, 0x00);
+
+    /* reading just before it is set suppresses it for the frame */
+    step_to(&mut ppu, 241, 1);
+    assert_eq!(ppu.read(RegType::PPUSTATUS) & 0x80, 0x00);
+    ppu.step();
+    assert_eq!(ppu.read(RegType::PPUSTATUS) & 0x80, 0x00);
+
+    /* but not for the next one */
+    ppu.step();
+    step_to(&mut ppu, 241, 2);
+    assert_eq!(ppu.read(RegType::PPUSTATUS) & 0x80, 0x80);
+}
+
+#[test]
+fn ppu_sprite_overflow_test() {
+    let mut ppu = Ppu::new(test_cartridge(0, Mirroring::Horizontal));
+
+    /* 9 sprites on scanline 20 */
+    for n in 0..64 {
+        ppu.sprite_ram[n * 4] = if n < 9 { 20 } else { 200 };
+    }
+    step_to(&mut ppu, 20, 258);
+    assert_eq!(ppu.secondary_oam.len(), 8);
+    assert!(ppu.status.contains(PpuStatus::SPRITE_OVERFLOW));
+
+    /* cleared at the pre-render line */
+    step_to(&mut ppu, 261, 2);
+    assert!(!ppu.status.contains(PpuStatus::SPRITE_OVERFLOW));
+
+    /*
+     * 8 sprites on the line and the 9th one is not, but the buggy
+     * evaluation reads the tile number of sprite 9 as its Y coordinate
+     */
+    ppu.sprite_ram[8 * 4] = 200;
+    ppu.sprite_ram[9 * 4 + 1] = 20;
+    step_to(&mut ppu, 20, 258);
+    assert!(ppu.status.contains(PpuStatus::SPRITE_OVERFLOW));
+}
+
+#[test]
+fn ppu_sprite_zero_hit_test() {
+    let mut ppu = Ppu::new(test_cartridge(0, Mirroring::Horizontal));
+    ppu.mask = PpuMask::empty();
+
+    /* tile 1: opaque right half */
+    for row in 0..8 {
+        ppu.vram.write(0x0010 + row, 0b0000_1111);
+    }
+    /* background: tile 1 in the top left corner only */
+    ppu.vram.write(0x2000, 1);
+    /* sprite 0: tile 1 at (0, 1) */
+    ppu.sprite_ram[0..4].copy_from_slice(&[0, 1, 0, 0]);
+
+    ppu.mask = PpuMask::SHOW_ALL;
+    step_to(&mut ppu, 1, 0);
+    assert!(!ppu.status.contains(PpuStatus::SPRITE_ZERO_HIT));
+    step_to(&mut ppu, 2, 0);
+    assert!(ppu.status.contains(PpuStatus::SPRITE_ZERO_HIT));
+
+    step_to(&mut ppu, 261, 2);
+    assert!(!ppu.status.contains(PpuStatus::SPRITE_ZERO_HIT));
+
+    /* no hit against a transparent background pixel */
+    ppu.sprite_ram[3] = 12;
+    step_to(&mut ppu, 10, 0);
+    assert!(!ppu.status.contains(PpuStatus::SPRITE_ZERO_HIT));
+}
+
+#[test]
+fn ppu_nmi_output_test() {
+    let mut ppu = Ppu::new(test_cartridge(0, Mirroring::Horizontal));
+    ppu.mask = PpuMask::empty();
+
+    step_to(&mut ppu, 241, 2);
+    assert!(!ppu.nmi_output());
+
+    /* enabling NMI during vblank asserts it right away */
+    ppu.write(RegType::PPUCTRL, 0b1000_0000);
+    assert!(ppu.nmi_output());
+
+    /* reading PPUSTATUS releases it */
+    ppu.read(RegType::PPUSTATUS);
+    assert!(!ppu.nmi_output());
+}
+
+#[test]
+fn ppu_sprite_pattern_test() {
+    let mut ppu = Ppu::new(test_cartridge(0, Mirroring::Horizontal));
+
+    /* 8x8: vertical flip reads the rows bottom up */
+    assert_eq!(ppu.sprite_pattern_addr(0x02, 0b0000_0000, 1), 0x0021);
+    assert_eq!(ppu.sprite_pattern_addr(0x02, 0b1000_0000, 1), 0x0026);
+    ppu.write(RegType::PPUCTRL, 0b0000_1000);
+    assert_eq!(ppu.sprite_pattern_addr(0x02, 0b0000_0000, 1), 0x1021);
+
+    /* 8x16: bit 0 of the tile selects the pattern table */
+    ppu.write(RegType::PPUCTRL, 0b0010_0000);
+    assert_eq!(ppu.sprite_pattern_addr(0x03, 0b0000_0000, 1), 0x1021);
+    assert_eq!(ppu.sprite_pattern_addr(0x03, 0b0000_0000, 9), 0x1031);
+    assert_eq!(ppu.sprite_pattern_addr(0x02, 0b0000_0000, 9), 0x0031);
+    /* vertical flip swaps the two tiles too */
+    assert_eq!(ppu.sprite_pattern_addr(0x02, 0b1000_0000, 1), 0x0036);
+    assert_eq!(ppu.sprite_pattern_addr(0x02, 0b1000_0000, 14), 0x0021);
+}
+
+#[test]
+fn ppu_sprite_flip_test() {
+    let mut ppu = Ppu::new(test_cartridge(0, Mirroring::Horizontal));
+    ppu.mask = PpuMask::empty();
+
+    /* tile 1: leftmost column of the top row set in both planes */
+    ppu.vram.write(0x0010, 0b1000_0000);
+    ppu.vram.write(0x0018, 0b1100_0000);
+    ppu.sprite_ram[0..4].copy_from_slice(&[9, 1, 0b0100_0011, 30]);
+    ppu.mask = PpuMask::SHOW_SPRITES;
+
+    /* fetched at the end of scanline 9 for the first row on scanline 10 */
+    step_to(&mut ppu, 9, 321);
+    assert_eq!(ppu.line_sprites.len(), 1);
+    assert_eq!(ppu.line_sprites[0].pattern_lo, 0b0000_0001);
+    assert_eq!(ppu.line_sprites[0].pattern_hi, 0b0000_0011);
+}
+
+#[test]
+fn ppu_sprite_priority_test() {
+    /* (pixel, palette) */
+    let bg = (1, 2);
+    let sprite = (3, 5);
+
+    assert_eq!(Ppu::multiplex(bg, sprite, false), sprite);
+    assert_eq!(Ppu::multiplex(bg, sprite, true), bg);
+    /* transparent background: sprite shows even when behind */
+    assert_eq!(Ppu::multiplex((0, 2), sprite, true), sprite);
+    /* transparent sprite */
+    assert_eq!(Ppu::multiplex(bg, (0, 5), false), bg);
+}
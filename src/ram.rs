@@ -1,3 +1,5 @@
+use crate::savestate::{StateError, StateReader, StateWriter};
+
 pub struct Ram {
     pub ram: Vec<u8>,
 }
@@ -13,4 +15,12 @@ impl Ram {
     pub fn read(&self, addr: u16) -> u8 {
         self.ram[addr as usize]
     }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.ram);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_bytes_into(&mut self.ram)
+    }
 }
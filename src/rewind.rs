@@ -0,0 +1,318 @@
+use std::collections::VecDeque;
+
+/*
+ * Rewind buffer
+ *
+ * A ring of save states captured every `interval` frames. Every
+ * KEYFRAME_INTERVAL-th state is kept as is (a keyframe); the others are
+ * stored as the XOR against the latest keyframe, run-length encoded.
+ * Consecutive frames only differ in a few hundred bytes, so a delta is
+ * a small fraction of a full state.
+ *
+ * Once `capacity` states are held, the oldest one is dropped. When that
+ * is a keyframe, its data is kept aside for the deltas still based on it.
+ */
+pub struct Rewind {
+    interval: u64,
+    capacity: usize,
+    snapshots: VecDeque<Snapshot>,
+    /* keyframe of the deltas at the front, already dropped itself */
+    evicted_base: Option<Vec<u8>>,
+}
+
+struct Snapshot {
+    frame: u64,
+    data: Data,
+}
+
+enum Data {
+    Keyframe(Vec<u8>),
+    Delta(Vec<u8>),
+}
+
+impl Data {
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        match self {
+            Data::Keyframe(data) | Data::Delta(data) => data.len(),
+        }
+    }
+}
+
+impl Rewind {
+    const KEYFRAME_INTERVAL: usize = 60;
+
+    pub fn new(interval: u64, capacity: usize) -> Rewind {
+        Rewind {
+            interval: interval.max(1),
+            capacity: capacity.max(1),
+            snapshots: VecDeque::new(),
+            evicted_base: None,
+        }
+    }
+
+    /*
+     * Whether the state at the start of this frame should be captured
+     */
+    pub fn due(&self, frame: u64) -> bool {
+        match self.snapshots.back() {
+            Some(last) => frame >= last.frame + self.interval,
+            None => true,
+        }
+    }
+
+    pub fn push(&mut self, frame: u64, state: Vec<u8>) {
+        let keyframe = self
+            .snapshots
+            .iter()
+            .rposition(|s| matches!(s.data, Data::Keyframe(_)));
+        let data = match keyframe {
+            Some(pos) if self.snapshots.len() - pos < Self::KEYFRAME_INTERVAL => {
+                match &self.snapshots[pos].data {
+                    Data::Keyframe(base) => Data::Delta(encode_delta(base, &state)),
+                    Data::Delta(_) => unreachable!(),
+                }
+            }
+            _ => Data::Keyframe(state),
+        };
+        self.snapshots.push_back(Snapshot { frame, data });
+
+        while self.snapshots.len() > self.capacity {
+            if let Some(Snapshot {
+                data: Data::Keyframe(base),
+                ..
+            }) = self.snapshots.pop_front()
+            {
+                self.evicted_base = Some(base);
+            }
+        }
+        self.drop_evicted_base();
+    }
+
+    /* once no delta at the front needs it */
+    fn drop_evicted_base(&mut self) {
+        match self.snapshots.front() {
+            Some(Snapshot {
+                data: Data::Delta(_),
+                ..
+            }) => {}
+            _ => self.evicted_base = None,
+        }
+    }
+
+    /* the keyframe the delta at `pos` is based on */
+    fn base_of(&self, pos: usize) -> &[u8] {
+        let keyframe = self
+            .snapshots
+            .iter()
+            .take(pos)
+            .rev()
+            .find_map(|s| match &s.data {
+                Data::Keyframe(base) => Some(base),
+                Data::Delta(_) => None,
+            });
+        match keyframe.or(self.evicted_base.as_ref()) {
+            Some(base) => base,
+            None => panic!("rewind delta without a keyframe"),
+        }
+    }
+
+    /*
+     * Forget the states captured after `frame` and return the latest
+     * remaining one, with the frame it was captured at
+     */
+    pub fn rewind_to(&mut self, frame: u64) -> Option<(u64, Vec<u8>)> {
+        while let Some(last) = self.snapshots.back() {
+            if last.frame <= frame {
+                break;
+            }
+            self.snapshots.pop_back();
+        }
+        self.drop_evicted_base();
+
+        let pos = self.snapshots.len().checked_sub(1)?;
+        let last = &self.snapshots[pos];
+        let state = match &last.data {
+            Data::Keyframe(state) => state.clone(),
+            Data::Delta(delta) => decode_delta(self.base_of(pos), delta),
+        };
+        Some((last.frame, state))
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.evicted_base = None;
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    /* bytes used by the captured states */
+    #[cfg(test)]
+    pub fn memory_usage(&self) -> usize {
+        let base = match &self.evicted_base {
+            Some(base) => base.len(),
+            None => 0,
+        };
+        base + self.snapshots.iter().map(|s| s.data.len()).sum::<usize>()
+    }
+}
+
+/*
+ * Delta format, all numbers as LEB128:
+ *
+ *   length of the state
+ *   repeated: number of unchanged bytes, number of changed bytes, the
+ *             changed bytes XORed with the base
+ *
+ * The base is read as zeros past its end.
+ */
+fn encode_delta(base: &[u8], state: &[u8]) -> Vec<u8> {
+    let xor = |i: usize| state[i] ^ base.get(i).cloned().unwrap_or(0);
+
+    let mut out = Vec::new();
+    write_varint(&mut out, state.len());
+    let mut i = 0;
+    while i < state.len() {
+        let start = i;
+        while i < state.len() && xor(i) == 0 {
+            i += 1;
+        }
+        write_varint(&mut out, i - start);
+
+        let start = i;
+        while i < state.len() && xor(i) != 0 {
+            i += 1;
+        }
+        write_varint(&mut out, i - start);
+        out.extend((start..i).map(xor));
+    }
+    out
+}
+
+fn decode_delta(base: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut pos = 0;
+    let len = read_varint(delta, &mut pos);
+
+    let mut state: Vec<u8> = (0..len)
+        .map(|i| base.get(i).cloned().unwrap_or(0))
+        .collect();
+    let mut i = 0;
+    while i < len {
+        i += read_varint(delta, &mut pos);
+        let changed = read_varint(delta, &mut pos);
+        for b in &mut state[i..i + changed] {
+            *b ^= delta[pos];
+            pos += 1;
+        }
+        i += changed;
+    }
+    state
+}
+
+fn write_varint(out: &mut Vec<u8>, mut v: usize) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> usize {
+    let mut v = 0;
+    let mut shift = 0;
+    loop {
+        let b = buf[*pos];
+        *pos += 1;
+        v |= ((b & 0x7F) as usize) << shift;
+        if (b & 0x80) == 0 {
+            return v;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* a 4KB "machine" where only a few bytes change every frame */
+    fn state(frame: u64) -> Vec<u8> {
+        let mut state: Vec<u8> = (0..0x1000).map(|i| (i * 7) as u8).collect();
+        state[0x10] = frame as u8;
+        state[0x800..0x808].copy_from_slice(&frame.to_le_bytes());
+        state
+    }
+
+    #[test]
+    fn test_delta() {
+        let base = state(0);
+        for next in [
+            state(1),
+            state(300),
+            base[..0x800].to_vec(),
+            vec![1; 0x1200],
+        ]
+        .iter()
+        {
+            let delta = encode_delta(&base, next);
+            assert_eq!(&decode_delta(&base, &delta), next);
+        }
+
+        assert!(encode_delta(&base, &state(1)).len() < 16);
+        assert_eq!(encode_delta(&base, &base).len(), 5);
+    }
+
+    #[test]
+    fn test_rewind_to() {
+        let mut rewind = Rewind::new(2, 1000);
+        for frame in 0..200 {
+            if rewind.due(frame) {
+                rewind.push(frame, state(frame));
+            }
+        }
+        assert_eq!(rewind.len(), 100);
+        assert!(!rewind.due(198));
+
+        assert_eq!(rewind.rewind_to(151), Some((150, state(150))));
+        assert_eq!(rewind.len(), 76);
+        assert_eq!(rewind.rewind_to(150), Some((150, state(150))));
+        // back to a keyframe
+        assert_eq!(rewind.rewind_to(121), Some((120, state(120))));
+
+        // the timeline continues from there
+        rewind.push(122, state(1122));
+        assert_eq!(rewind.rewind_to(200), Some((122, state(1122))));
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut rewind = Rewind::new(1, 100);
+        for frame in 0..1000 {
+            rewind.push(frame, state(frame));
+            assert!(rewind.len() <= 100);
+        }
+        assert_eq!(rewind.len(), 100);
+        assert_eq!(rewind.rewind_to(0), None);
+        assert!(rewind.memory_usage() < 4 * 0x1000);
+    }
+
+    #[test]
+    fn test_small_capacity() {
+        // fewer states than a keyframe interval
+        let mut rewind = Rewind::new(1, 10);
+        for frame in 0..200 {
+            rewind.push(frame, state(frame));
+            assert_eq!(rewind.len(), (frame as usize + 1).min(10));
+        }
+
+        // the deltas whose keyframe was evicted still decode
+        assert_eq!(rewind.rewind_to(195), Some((195, state(195))));
+        assert_eq!(rewind.rewind_to(190), Some((190, state(190))));
+        assert_eq!(rewind.rewind_to(189), None);
+        assert_eq!(rewind.len(), 0);
+        assert_eq!(rewind.memory_usage(), 0);
+    }
+}
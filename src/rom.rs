@@ -1,27 +1,290 @@
+use crate::mapper::Mirroring;
+
+use std::error;
+use std::fmt;
+use std::io;
+
 pub const INES_HEADER_SIZE: usize = 0x0010;
+pub const TRAINER_SIZE: usize = 0x0200;
+
+const PRG_ROM_UNIT: usize = 0x4000; // 16KiB
+const CHR_ROM_UNIT: usize = 0x2000; // 8KiB
+
+/// Why an iNES image could not be loaded. Nes::load() and
+/// Nes::from_bytes() return it wrapped in an io::Error of kind InvalidData.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RomError {
+    /* shorter than the 16 byte header */
+    TooShort,
+    /* does not start with "NES" followed by $1A */
+    BadMagic,
+    /* the header promises more data than the file has */
+    Truncated { expected: usize, actual: usize },
+    /* less PRG-ROM than the 16KiB every board maps at $C000-$FFFF */
+    PrgRomTooSmall(usize),
+}
 
-pub fn load(rom: Vec<u8>) -> Option<(ProgramRom, CharacterRom)> {
-    if rom.len() < 16 || rom[0..3] != ['N' as u8, 'E' as u8, 'S' as u8] {
-        return None;
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::TooShort => write!(f, "ROM is shorter than the iNES header"),
+            RomError::BadMagic => write!(f, "Not an NES ROM"),
+            RomError::Truncated { expected, actual } => write!(
+                f,
+                "ROM is truncated: expected {} bytes, got {}",
+                expected, actual
+            ),
+            RomError::PrgRomTooSmall(size) => {
+                write!(f, "PRG-ROM is too small: {} bytes", size)
+            }
+        }
     }
+}
+
+impl error::Error for RomError {}
 
-    let program_rom_size = rom[4] as usize;
-    let character_rom_size = rom[5] as usize;
+impl From<RomError> for io::Error {
+    fn from(e: RomError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
 
-    let trainer_size = if (rom[6] & 0b0000_0100) == 0b0000_0100 {
-        0x200
+/// Which revision of the header the file uses
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HeaderFormat {
+    /* archaic iNES: bytes 7-15 may contain garbage */
+    ArchaicINes,
+    INes,
+    Nes20,
+}
+
+/// The console the cartridge was made for
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem { ppu_type: u8, hardware_type: u8 },
+    Playchoice10,
+    /* NES 2.0 byte 13 */
+    Extended(u8),
+}
+
+/// CPU/PPU timing the cartridge expects
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    MultiRegion,
+    Dendy,
+}
+
+/// Everything the iNES / NES 2.0 header tells about the cartridge.
+/// Sizes are in bytes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RomHeader {
+    pub format: HeaderFormat,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub trainer: bool,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub timing: Timing,
+    pub console_type: ConsoleType,
+    pub misc_roms: u8,
+    pub default_expansion_device: u8,
+}
+
+impl Default for RomHeader {
+    fn default() -> RomHeader {
+        RomHeader {
+            format: HeaderFormat::INes,
+            prg_rom_size: 0,
+            chr_rom_size: 0,
+            mirroring: Mirroring::Horizontal,
+            battery: false,
+            trainer: false,
+            mapper: 0,
+            submapper: 0,
+            prg_ram_size: 0x2000,
+            prg_nvram_size: 0,
+            chr_ram_size: 0,
+            chr_nvram_size: 0,
+            timing: Timing::Ntsc,
+            console_type: ConsoleType::Nes,
+            misc_roms: 0,
+            default_expansion_device: 0,
+        }
+    }
+}
+
+impl RomHeader {
+    pub fn parse(header: &[u8]) -> Result<RomHeader, RomError> {
+        if header.len() < INES_HEADER_SIZE {
+            return Err(RomError::TooShort);
+        }
+        if header[0..4] != [b'N', b'E', b'S', 0x1A] {
+            return Err(RomError::BadMagic);
+        }
+
+        let flags6 = header[6];
+        let flags7 = header[7];
+
+        let format = if (flags7 & 0b0000_1100) == 0b0000_1000 {
+            HeaderFormat::Nes20
+        } else if (flags7 & 0b0000_1100) == 0 && header[12..16].iter().all(|b| *b == 0) {
+            HeaderFormat::INes
+        } else {
+            /* e.g. "DiskDude!" scribbled over bytes 7-15 */
+            HeaderFormat::ArchaicINes
+        };
+
+        let mirroring = if (flags6 & 0b0000_1000) != 0 {
+            Mirroring::FourScreen
+        } else if (flags6 & 0b0000_0001) != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+
+        let mut rom_header = RomHeader {
+            format,
+            mirroring,
+            battery: (flags6 & 0b0000_0010) != 0,
+            trainer: (flags6 & 0b0000_0100) != 0,
+            mapper: (flags6 >> 4) as u16,
+            ..Default::default()
+        };
+
+        match format {
+            HeaderFormat::ArchaicINes => {
+                rom_header.prg_rom_size = header[4] as usize * PRG_ROM_UNIT;
+                rom_header.chr_rom_size = header[5] as usize * CHR_ROM_UNIT;
+            }
+            HeaderFormat::INes => {
+                rom_header.prg_rom_size = header[4] as usize * PRG_ROM_UNIT;
+                rom_header.chr_rom_size = header[5] as usize * CHR_ROM_UNIT;
+                rom_header.mapper |= (flags7 & 0xF0) as u16;
+                rom_header.console_type = match flags7 & 0b0000_0011 {
+                    1 => ConsoleType::VsSystem {
+                        ppu_type: 0,
+                        hardware_type: 0,
+                    },
+                    2 => ConsoleType::Playchoice10,
+                    _ => ConsoleType::Nes,
+                };
+                /* 0 means 8KiB for compatibility */
+                rom_header.prg_ram_size = header[8].max(1) as usize * 0x2000;
+                rom_header.timing = if (header[9] & 1) != 0 {
+                    Timing::Pal
+                } else {
+                    Timing::Ntsc
+                };
+            }
+            HeaderFormat::Nes20 => {
+                rom_header.prg_rom_size =
+                    Self::nes20_rom_size(header[4], header[9] & 0x0F, PRG_ROM_UNIT);
+                rom_header.chr_rom_size =
+                    Self::nes20_rom_size(header[5], header[9] >> 4, CHR_ROM_UNIT);
+                rom_header.mapper |= (flags7 & 0xF0) as u16 | ((header[8] & 0x0F) as u16) << 8;
+                rom_header.submapper = header[8] >> 4;
+                rom_header.prg_ram_size = Self::nes20_ram_size(header[10] & 0x0F);
+                rom_header.prg_nvram_size = Self::nes20_ram_size(header[10] >> 4);
+                rom_header.chr_ram_size = Self::nes20_ram_size(header[11] & 0x0F);
+                rom_header.chr_nvram_size = Self::nes20_ram_size(header[11] >> 4);
+                rom_header.timing = match header[12] & 0b0000_0011 {
+                    0 => Timing::Ntsc,
+                    1 => Timing::Pal,
+                    2 => Timing::MultiRegion,
+                    _ => Timing::Dendy,
+                };
+                rom_header.console_type = match flags7 & 0b0000_0011 {
+                    0 => ConsoleType::Nes,
+                    1 => ConsoleType::VsSystem {
+                        ppu_type: header[13] & 0x0F,
+                        hardware_type: header[13] >> 4,
+                    },
+                    2 => ConsoleType::Playchoice10,
+                    _ => ConsoleType::Extended(header[13] & 0x0F),
+                };
+                rom_header.misc_roms = header[14] & 0b0000_0011;
+                rom_header.default_expansion_device = header[15] & 0b0011_1111;
+            }
+        }
+
+        /* iNES leaves CHR-RAM implied by the absence of CHR-ROM */
+        if format != HeaderFormat::Nes20 && rom_header.chr_rom_size == 0 {
+            rom_header.chr_ram_size = 0x2000;
+        }
+
+        Ok(rom_header)
+    }
+
+    /*
+     * NES 2.0 ROM sizes: 12 bit count of units, or
+     * 2^E * (MM*2+1) bytes when the MSB nibble is $F (LSB: EEEEEEMM)
+     */
+    fn nes20_rom_size(lsb: u8, msb: u8, unit: usize) -> usize {
+        if msb == 0x0F {
+            let exponent = (lsb >> 2) as u32;
+            let multiplier = (lsb & 0b0000_0011) as usize * 2 + 1;
+            2usize.saturating_pow(exponent).saturating_mul(multiplier)
+        } else {
+            (((msb as usize) << 8) | lsb as usize) * unit
+        }
+    }
+
+    /* NES 2.0 RAM sizes: 0 means none, otherwise 64 << shift bytes */
+    fn nes20_ram_size(shift: u8) -> usize {
+        if shift == 0 {
+            0
+        } else {
+            64 << shift
+        }
+    }
+}
+
+pub struct Rom {
+    pub header: RomHeader,
+    pub prog_rom: ProgramRom,
+    pub chr_rom: CharacterRom,
+}
+
+pub fn load(rom: Vec<u8>) -> Result<Rom, RomError> {
+    let header = RomHeader::parse(&rom)?;
+    if header.prg_rom_size < PRG_ROM_UNIT {
+        return Err(RomError::PrgRomTooSmall(header.prg_rom_size));
+    }
+
+    let trainer_start = INES_HEADER_SIZE;
+    let program_rom_start = if header.trainer {
+        trainer_start + TRAINER_SIZE
     } else {
-        0x0
+        trainer_start
+    };
+    /* NES 2.0 exponent sizes can be huge: too big for any file */
+    let character_rom_start = program_rom_start.checked_add(header.prg_rom_size);
+    let character_rom_end =
+        character_rom_start.and_then(|start| start.checked_add(header.chr_rom_size));
+    let (character_rom_start, character_rom_end) = match (character_rom_start, character_rom_end) {
+        (Some(start), Some(end)) if end <= rom.len() => (start, end),
+        (_, end) => {
+            return Err(RomError::Truncated {
+                expected: end.unwrap_or(usize::MAX),
+                actual: rom.len(),
+            })
+        }
     };
 
-    let character_rom_start =
-        (INES_HEADER_SIZE + trainer_size + program_rom_size * 0x4000) as usize; //16KiB -> 0x4000
-    let character_rom_end = (character_rom_start + character_rom_size * 0x2000) as usize; //8Kib  -> 0x2000
-
-    Some((
-        ProgramRom::new(&rom[INES_HEADER_SIZE..character_rom_start]),
-        CharacterRom::new(&rom[character_rom_start..character_rom_end]),
-    ))
+    Ok(Rom {
+        prog_rom: ProgramRom::new(&rom[program_rom_start..character_rom_start]),
+        chr_rom: CharacterRom::new(&rom[character_rom_start..character_rom_end]),
+        header,
+    })
 }
 
 pub struct ProgramRom {
@@ -36,7 +299,7 @@ impl ProgramRom {
     }
 
     pub fn read(&self, addr: u16) -> u8 {
-        return self.data[addr as usize];
+        self.data[addr as usize]
     }
 }
 
@@ -46,9 +309,201 @@ pub struct CharacterRom {
 
 impl CharacterRom {
     pub fn new(data: &[u8]) -> CharacterRom {
-        println!("Character ROM size: 0x{:x}", data.len());
         CharacterRom {
             data: data.to_vec(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(header: [u8; 16], body_size: usize) -> Vec<u8> {
+        let mut rom = header.to_vec();
+        rom.extend((0..body_size).map(|i| i as u8));
+        rom
+    }
+
+    #[test]
+    fn test_ines_header() {
+        let header = RomHeader::parse(&[
+            b'N',
+            b'E',
+            b'S',
+            0x1A,
+            2,
+            1,
+            0b0100_0011,
+            0b0001_0000,
+            0,
+            1,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ])
+        .unwrap();
+
+        assert_eq!(header.format, HeaderFormat::INes);
+        assert_eq!(header.prg_rom_size, 0x8000);
+        assert_eq!(header.chr_rom_size, 0x2000);
+        assert_eq!(header.mirroring, Mirroring::Vertical);
+        assert!(header.battery);
+        assert!(!header.trainer);
+        assert_eq!(header.mapper, 0x14);
+        assert_eq!(header.prg_ram_size, 0x2000);
+        assert_eq!(header.chr_ram_size, 0);
+        assert_eq!(header.timing, Timing::Pal);
+    }
+
+    #[test]
+    fn test_archaic_ines_header() {
+        let header = RomHeader::parse(&[
+            b'N',
+            b'E',
+            b'S',
+            0x1A,
+            1,
+            0,
+            0b0001_1000,
+            b'D',
+            b'i',
+            b's',
+            b'k',
+            b'D',
+            b'u',
+            b'd',
+            b'e',
+            b'!',
+        ])
+        .unwrap();
+
+        /* the upper mapper nibble is garbage */
+        assert_eq!(header.format, HeaderFormat::ArchaicINes);
+        assert_eq!(header.mapper, 1);
+        assert_eq!(header.mirroring, Mirroring::FourScreen);
+        assert_eq!(header.chr_ram_size, 0x2000);
+    }
+
+    #[test]
+    fn test_nes20_header() {
+        let header = RomHeader::parse(&[
+            b'N',
+            b'E',
+            b'S',
+            0x1A,
+            0x02,
+            0x07,
+            0b0100_0000,
+            0b0001_1001,
+            0b0011_0001,
+            0xF0,
+            0b0111_0000,
+            0b0000_0111,
+            0x03,
+            0b0001_0010,
+            0x01,
+            0x01,
+        ])
+        .unwrap();
+
+        assert_eq!(header.format, HeaderFormat::Nes20);
+        assert_eq!(header.prg_rom_size, 0x8000);
+        /* exponent-multiplier notation: 2^1 * (3*2+1) */
+        assert_eq!(header.chr_rom_size, 14);
+        assert_eq!(header.mapper, 0x114);
+        assert_eq!(header.submapper, 3);
+        assert_eq!(header.prg_ram_size, 0);
+        assert_eq!(header.prg_nvram_size, 0x2000);
+        assert_eq!(header.chr_ram_size, 0x2000);
+        assert_eq!(header.chr_nvram_size, 0);
+        assert_eq!(header.timing, Timing::Dendy);
+        assert_eq!(
+            header.console_type,
+            ConsoleType::VsSystem {
+                ppu_type: 2,
+                hardware_type: 1
+            }
+        );
+        assert_eq!(header.misc_roms, 1);
+        assert_eq!(header.default_expansion_device, 1);
+    }
+
+    #[test]
+    fn test_load_skips_trainer() {
+        let header = [
+            b'N',
+            b'E',
+            b'S',
+            0x1A,
+            1,
+            1,
+            0b0000_0100,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ];
+        let rom = load(image(header, TRAINER_SIZE + PRG_ROM_UNIT + CHR_ROM_UNIT)).unwrap();
+
+        assert_eq!(rom.prog_rom.data.len(), PRG_ROM_UNIT);
+        assert_eq!(rom.prog_rom.data[0], TRAINER_SIZE as u8);
+        assert_eq!(rom.chr_rom.data.len(), CHR_ROM_UNIT);
+    }
+
+    #[test]
+    fn test_load_errors() {
+        assert_eq!(load(vec![0; 8]).err(), Some(RomError::TooShort));
+
+        let header = [b'N', b'E', b'S', 0x00, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(load(image(header, 0)).err(), Some(RomError::BadMagic));
+
+        let header = [b'N', b'E', b'S', 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(
+            load(image(header, PRG_ROM_UNIT)).err(),
+            Some(RomError::Truncated {
+                expected: INES_HEADER_SIZE + PRG_ROM_UNIT + CHR_ROM_UNIT,
+                actual: INES_HEADER_SIZE + PRG_ROM_UNIT,
+            })
+        );
+
+        /* NES 2.0 exponent notation: 2^63 * 7 bytes of PRG-ROM */
+        let header = [
+            b'N', b'E', b'S', 0x1A, 0xFF, 1, 0, 0x08, 0, 0x0F, 0, 0, 0, 0, 0, 0,
+        ];
+        assert_eq!(
+            load(image(header, PRG_ROM_UNIT)).err(),
+            Some(RomError::Truncated {
+                expected: usize::MAX,
+                actual: INES_HEADER_SIZE + PRG_ROM_UNIT,
+            })
+        );
+    }
+
+    #[test]
+    fn test_load_small_prg_rom() {
+        /* iNES: no PRG-ROM at all */
+        let header = [b'N', b'E', b'S', 0x1A, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(
+            load(image(header, CHR_ROM_UNIT)).err(),
+            Some(RomError::PrgRomTooSmall(0))
+        );
+
+        /* NES 2.0 exponent notation: $34 is 2^13 * 1 bytes, half a bank */
+        let header = [
+            b'N', b'E', b'S', 0x1A, 0x34, 1, 0, 0x08, 0, 0x0F, 0, 0, 0, 0, 0, 0,
+        ];
+        assert_eq!(
+            load(image(header, 0x2000 + CHR_ROM_UNIT)).err(),
+            Some(RomError::PrgRomTooSmall(0x2000))
+        );
+    }
+}
@@ -0,0 +1,276 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+/*
+ * Save state format
+ *
+ *   "TNES"              magic
+ *   u32                 format version
+ *   u64                 hash of the ROM file the state belongs to
+ *   ...                 the state of each component, in a fixed order
+ *
+ * All integers are little endian. Byte arrays are prefixed by their
+ * length as u32. Bump VERSION whenever a component changes what it saves.
+ */
+const MAGIC: &[u8; 4] = b"TNES";
+pub const VERSION: u32 = 2;
+
+/// Why a save state could not be loaded
+#[derive(Debug, PartialEq, Eq)]
+pub enum StateError {
+    /* does not start with "TNES" */
+    BadMagic,
+    /* written by an incompatible version of the emulator */
+    UnsupportedVersion(u32),
+    /* saved while running another game */
+    RomMismatch,
+    /* ends before all the components are restored */
+    Truncated,
+    /* a value that cannot come from this machine */
+    Corrupted(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion(v) => write!(
+                f,
+                "Save state version {} is not supported (expected {})",
+                v, VERSION
+            ),
+            StateError::RomMismatch => write!(f, "Save state is for another ROM"),
+            StateError::Truncated => write!(f, "Save state is truncated"),
+            StateError::Corrupted(what) => write!(f, "Save state is corrupted: {}", what),
+        }
+    }
+}
+
+impl error::Error for StateError {}
+
+impl From<StateError> for io::Error {
+    fn from(e: StateError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/*
+ * 64-bit FNV-1a, to tell which ROM a state was saved with
+ */
+pub fn rom_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new(rom_hash: u64) -> StateWriter {
+        let mut w = StateWriter { buf: Vec::new() };
+        w.buf.extend_from_slice(MAGIC);
+        w.write_u32(VERSION);
+        w.write_u64(rom_hash);
+        w
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn write_u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub fn write_bool(&mut self, v: bool) {
+        self.write_u8(v as u8);
+    }
+
+    pub fn write_u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn write_option_u8(&mut self, v: Option<u8>) {
+        self.write_bool(v.is_some());
+        self.write_u8(v.unwrap_or(0));
+    }
+
+    pub fn write_bytes(&mut self, v: &[u8]) {
+        self.write_u32(v.len() as u32);
+        self.buf.extend_from_slice(v);
+    }
+}
+
+pub struct StateReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    /*
+     * Check the header and position the reader at the first component
+     */
+    pub fn new(buf: &'a [u8], rom_hash: u64) -> Result<StateReader<'a>, StateError> {
+        if buf.len() < MAGIC.len() || &buf[..MAGIC.len()] != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+
+        let mut r = StateReader {
+            buf: &buf[MAGIC.len()..],
+        };
+        let version = r.read_u32()?;
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        if r.read_u64()? != rom_hash {
+            return Err(StateError::RomMismatch);
+        }
+        Ok(r)
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], StateError> {
+        if self.buf.len() < n {
+            return Err(StateError::Truncated);
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Corrupted("boolean out of range")),
+        }
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn read_option_u8(&mut self) -> Result<Option<u8>, StateError> {
+        let some = self.read_bool()?;
+        let v = self.read_u8()?;
+        Ok(if some { Some(v) } else { None })
+    }
+
+    /*
+     * Read a byte array into a buffer of the same size
+     */
+    pub fn read_bytes_into(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+        let len = self.read_u32()? as usize;
+        if len != out.len() {
+            return Err(StateError::Corrupted("memory size mismatch"));
+        }
+        out.copy_from_slice(self.take(len)?);
+        Ok(())
+    }
+
+    /* everything has been read */
+    pub fn finish(&self) -> Result<(), StateError> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            Err(StateError::Corrupted("trailing data"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut w = StateWriter::new(42);
+        w.write_u8(0x12);
+        w.write_bool(true);
+        w.write_u16(0x3456);
+        w.write_u32(0x789A_BCDE);
+        w.write_u64(u64::MAX);
+        w.write_option_u8(Some(7));
+        w.write_option_u8(None);
+        w.write_bytes(&[1, 2, 3]);
+        let buf = w.into_inner();
+
+        let mut r = StateReader::new(&buf, 42).unwrap();
+        assert_eq!(r.read_u8(), Ok(0x12));
+        assert_eq!(r.read_bool(), Ok(true));
+        assert_eq!(r.read_u16(), Ok(0x3456));
+        assert_eq!(r.read_u32(), Ok(0x789A_BCDE));
+        assert_eq!(r.read_u64(), Ok(u64::MAX));
+        assert_eq!(r.read_option_u8(), Ok(Some(7)));
+        assert_eq!(r.read_option_u8(), Ok(None));
+        let mut bytes = [0; 3];
+        assert_eq!(r.read_bytes_into(&mut bytes), Ok(()));
+        assert_eq!(bytes, [1, 2, 3]);
+        assert_eq!(r.finish(), Ok(()));
+
+        // running past the end
+        assert_eq!(r.read_u8(), Err(StateError::Truncated));
+    }
+
+    #[test]
+    fn test_header() {
+        let mut w = StateWriter::new(rom_hash(b"game"));
+        w.write_u8(0);
+        let buf = w.into_inner();
+
+        assert!(StateReader::new(&buf, rom_hash(b"game")).is_ok());
+        assert_eq!(
+            StateReader::new(&buf, rom_hash(b"other game")).err(),
+            Some(StateError::RomMismatch)
+        );
+        assert_eq!(
+            StateReader::new(b"NES\x1a", 0).err(),
+            Some(StateError::BadMagic)
+        );
+
+        let mut newer = buf.clone();
+        newer[4] = VERSION as u8 + 1;
+        assert_eq!(
+            StateReader::new(&newer, rom_hash(b"game")).err(),
+            Some(StateError::UnsupportedVersion(VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn test_size_mismatch() {
+        let mut w = StateWriter::new(0);
+        w.write_bytes(&[0; 0x800]);
+        let buf = w.into_inner();
+
+        let mut r = StateReader::new(&buf, 0).unwrap();
+        let mut ram = vec![0; 0x2000];
+        assert!(r.read_bytes_into(&mut ram).is_err());
+    }
+}
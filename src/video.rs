@@ -0,0 +1,50 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Where the pictures go
+///
+/// present() is called once per frame, at the start of vblank, with
+/// SCREEN_WIDTH x SCREEN_HEIGHT pixels in 24-bit RGB, row by row.
+pub trait VideoSink {
+    fn present(&mut self, rgb: &[u8]);
+}
+
+/// Throws the pictures away (headless runs)
+pub struct NullSink;
+
+impl VideoSink for NullSink {
+    fn present(&mut self, _rgb: &[u8]) {}
+}
+
+/// Keeps every picture in memory. Clones share the same storage, so a
+/// test can hand one to the emulator and look at the frames with another.
+#[derive(Clone, Default)]
+pub struct MemorySink {
+    frames: Rc<RefCell<Vec<Vec<u8>>>>,
+}
+
+impl MemorySink {
+    pub fn new() -> MemorySink {
+        Default::default()
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.borrow().len()
+    }
+
+    /// The n-th picture presented, counting from 0
+    pub fn frame(&self, n: usize) -> Option<Vec<u8>> {
+        self.frames.borrow().get(n).cloned()
+    }
+
+    /// The latest picture presented
+    pub fn last_frame(&self) -> Option<Vec<u8>> {
+        self.frames.borrow().last().cloned()
+    }
+}
+
+impl VideoSink for MemorySink {
+    fn present(&mut self, rgb: &[u8]) {
+        self.frames.borrow_mut().push(rgb.to_vec());
+    }
+}
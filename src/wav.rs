@@ -0,0 +1,172 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::apu::Channel;
+
+const HEADER_SIZE: u32 = 44;
+
+/*
+ * 16-bit mono PCM WAV writer
+ *
+ * The sizes in the header are brought up to date after every write, so
+ * the file stays playable even if the emulator is killed.
+ */
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    data_size: u32,
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32) -> io::Result<Self> {
+        WavWriter::new(BufWriter::new(File::create(path)?), sample_rate)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut writer: W, sample_rate: u32) -> io::Result<WavWriter<W>> {
+        let channels: u16 = 1;
+        let bits_per_sample: u16 = 16;
+        let block_align = channels * bits_per_sample / 8;
+
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        writer.write_all(&1u16.to_le_bytes())?; // PCM
+        writer.write_all(&channels.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&bits_per_sample.to_le_bytes())?;
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+
+        Ok(WavWriter {
+            writer,
+            data_size: 0,
+        })
+    }
+
+    /*
+     * Append samples in -1.0..1.0 (clipped outside of it)
+     */
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            let pcm = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.writer.write_all(&pcm.to_le_bytes())?;
+        }
+        self.data_size += samples.len() as u32 * 2;
+
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer
+            .write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(40))?;
+        self.writer.write_all(&self.data_size.to_le_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()
+    }
+
+    #[cfg(test)]
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// 16-bit mono WAV files for the audio: the mix and, optionally, one
+/// file per channel next to it (game.wav -> game.pulse1.wav, ...).
+///
+/// The headers are kept up to date after every write, so the files stay
+/// playable even if the process is killed.
+pub struct WavSink {
+    mixed: WavWriter<BufWriter<File>>,
+    channels: Vec<(Channel, WavWriter<BufWriter<File>>)>,
+}
+
+impl WavSink {
+    /// Create the file at `path`, and the per channel files if asked to
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        sample_rate: u32,
+        per_channel: bool,
+    ) -> io::Result<WavSink> {
+        let path = path.as_ref();
+        let mut channels = Vec::new();
+        if per_channel {
+            for channel in Channel::ALL.iter() {
+                let writer = WavWriter::create(Self::channel_path(path, *channel), sample_rate)?;
+                channels.push((*channel, writer));
+            }
+        }
+
+        Ok(WavSink {
+            mixed: WavWriter::create(path, sample_rate)?,
+            channels,
+        })
+    }
+
+    fn channel_path(path: &Path, channel: Channel) -> PathBuf {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        path.with_file_name(format!("{}.{}.wav", stem, channel.name()))
+    }
+
+    /// Whether there is one file per channel
+    pub fn per_channel(&self) -> bool {
+        !self.channels.is_empty()
+    }
+
+    /// Append samples in -1.0..1.0 to the mix
+    pub fn write_mixed(&mut self, samples: &[f32]) -> io::Result<()> {
+        self.mixed.write_samples(samples)
+    }
+
+    /// Append samples to the file of `channel` (ignored without per
+    /// channel files)
+    pub fn write_channel(&mut self, channel: Channel, samples: &[f32]) -> io::Result<()> {
+        match self.channels.iter_mut().find(|(c, _)| *c == channel) {
+            Some((_, writer)) => writer.write_samples(samples),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn u32_at(buf: &[u8], offset: usize) -> u32 {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&buf[offset..offset + 4]);
+        u32::from_le_bytes(bytes)
+    }
+
+    #[test]
+    fn test_wav_writer() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 44100).unwrap();
+        wav.write_samples(&[0.0, 1.0]).unwrap();
+        wav.write_samples(&[-1.0, 2.0, 0.5]).unwrap();
+        let buf = wav.into_inner().into_inner();
+
+        assert_eq!(&buf[0..4], b"RIFF");
+        assert_eq!(u32_at(&buf, 4), 36 + 10);
+        assert_eq!(&buf[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&buf, 24), 44100);
+        assert_eq!(u32_at(&buf, 28), 88200);
+        assert_eq!(&buf[36..40], b"data");
+        assert_eq!(u32_at(&buf, 40), 10);
+
+        let pcm: Vec<i16> = buf[44..]
+            .chunks(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        assert_eq!(pcm, [0, 32767, -32767, 32767, 16383]);
+    }
+
+    #[test]
+    fn test_channel_path() {
+        let path = WavSink::channel_path(Path::new("out/smb.wav"), Channel::Triangle);
+        assert_eq!(path, Path::new("out/smb.triangle.wav"));
+    }
+}
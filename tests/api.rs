@@ -0,0 +1,137 @@
+use nes::{
+    Button, Channel, MemorySink, Mirroring, Nes, Player, RomError, StateError, SCREEN_HEIGHT,
+    SCREEN_WIDTH,
+};
+use std::io;
+
+/*
+ * NROM-128 that reads the first controller at every NMI and sets the
+ * backdrop colour to $21 while A is held, $00 otherwise
+ */
+fn configure_image() -> Vec<u8> {
+    let mut prg = vec![0; 0x4000];
+    #[rustfmt::skip]
+    let prog = [0xA9, 0x80,       //C000: LDA #$80
+                0x8D, 0x00, 0x20, //C002: STA $2000
+                0x4C, 0x05, 0xC0, //C005: JMP $C005
+                0xAD, 0x02, 0x20, //C008: LDA $2002
+                0xA9, 0x3F,       //C00B: LDA #$3F
+                0x8D, 0x06, 0x20, //C00D: STA $2006
+                0xA9, 0x00,       //C010: LDA #$00
+                0x8D, 0x06, 0x20, //C012: STA $2006
+                0xA9, 0x01,       //C015: LDA #$01
+                0x8D, 0x16, 0x40, //C017: STA $4016
+                0xA9, 0x00,       //C01A: LDA #$00
+                0x8D, 0x16, 0x40, //C01C: STA $4016
+                0xAD, 0x16, 0x40, //C01F: LDA $4016
+                0x29, 0x01,       //C022: AND #$01
+                0xF0, 0x02,       //C024: BEQ $C028
+                0xA9, 0x21,       //C026: LDA #$21
+                0x8D, 0x07, 0x20, //C028: STA $2007
+                0x40,             //C02B: RTI
+    ];
+    prg[..prog.len()].copy_from_slice(&prog);
+    // NMI, reset and IRQ vectors
+    prg[0x3FFA..].copy_from_slice(&[0x08, 0xC0, 0x00, 0xC0, 0x2B, 0xC0]);
+
+    let mut image = vec![b'N', b'E', b'S', 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    image.extend(prg);
+    image.extend(vec![0; 0x2000]);
+    image
+}
+
+fn configure_nes() -> Nes {
+    Nes::from_bytes(configure_image()).unwrap()
+}
+
+fn backdrop(nes: &Nes) -> Option<u8> {
+    let pixels = nes.indexed_frame_buffer();
+    assert_eq!(pixels.len(), SCREEN_WIDTH * SCREEN_HEIGHT);
+    if pixels.iter().all(|&c| c == pixels[0]) {
+        Some(pixels[0])
+    } else {
+        None
+    }
+}
+
+#[test]
+fn test_input_to_picture() {
+    let mut nes = configure_nes();
+    let video = MemorySink::new();
+    nes.set_video_sink(Box::new(video.clone()));
+
+    nes.run_frame();
+    nes.set_button(Player::One, Button::A, true);
+    nes.run_frame();
+    nes.run_frame();
+    assert_eq!(backdrop(&nes), Some(0x21));
+
+    nes.set_button(Player::One, Button::A, false);
+    nes.run_frame();
+    nes.run_frame();
+    assert_eq!(backdrop(&nes), Some(0x00));
+
+    assert_eq!(video.frame_count(), 5);
+    assert_ne!(video.frame(2), video.frame(4));
+    assert_eq!(video.last_frame().as_deref(), Some(nes.frame_buffer()));
+}
+
+#[test]
+fn test_save_state() {
+    let mut nes = configure_nes();
+    nes.set_button(Player::One, Button::A, true);
+    nes.run_frame();
+    let state = nes.save_state();
+
+    nes.set_button(Player::One, Button::A, false);
+    nes.run_frame();
+    nes.run_frame();
+    assert_eq!(backdrop(&nes), Some(0x00));
+
+    // the pressed button comes back with the state
+    nes.load_state(&state).unwrap();
+    nes.run_frame();
+    nes.run_frame();
+    assert_eq!(backdrop(&nes), Some(0x21));
+
+    assert_eq!(nes.load_state(b"junk"), Err(StateError::BadMagic));
+}
+
+#[test]
+fn test_cartridge_info() {
+    let nes = configure_nes();
+    let info = nes.cartridge_info();
+    assert_eq!(info.mapper, 0);
+    assert_eq!(info.prg_rom_size, 0x4000);
+    assert_eq!(info.chr_rom_size, 0x2000);
+    assert_eq!(info.mirroring, Mirroring::Horizontal);
+
+    let mut image = configure_image();
+    image.truncate(0x1000);
+    let err = Nes::from_bytes(image).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(
+        err.get_ref().unwrap().downcast_ref::<RomError>(),
+        Some(&RomError::Truncated {
+            expected: 0x6010,
+            actual: 0x1000
+        })
+    );
+}
+
+#[test]
+fn test_channel_samples() {
+    let mut nes = configure_nes();
+    let mut buf = vec![0.0; 4096];
+    nes.run_frame();
+    nes.read_samples(&mut buf);
+    assert_eq!(nes.read_channel_samples(Channel::Pulse1, &mut buf), 0);
+
+    nes.set_channel_outputs(true);
+    nes.run_frame();
+    let n = nes.read_samples(&mut buf);
+    assert!(n > 0);
+    for channel in Channel::ALL.iter() {
+        assert_eq!(nes.read_channel_samples(*channel, &mut buf), n);
+    }
+}